@@ -0,0 +1,145 @@
+/*
+    Which pages a sync added, removed or changed. Every sync records the commit a repo
+    moved from and to in the sync state, the page files that differ between two of
+    those commits come from a libgit2 tree diff. Archives have no commits to compare.
+*/
+
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{ Result, anyhow };
+use git2::{ Delta, Oid, Repository };
+use crate::config::RepoConfig;
+use crate::state::{ SyncState, now };
+use crate::sync::checkout_dir;
+
+/// Page changes of one repo, pages are named by their path in the repo without `.md`.
+#[derive(Debug, Default)]
+pub struct PageChanges {
+    pub name: String,
+    /// None when the repo got cloned in between, there is nothing to compare to then
+    pub from: Option<String>,
+    pub to: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl PageChanges {
+    pub fn is_empty(&self) -> bool {
+        self.from.is_some()
+            && self.error.is_none()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+    }
+}
+
+/// Changes of every repo made by syncs in the last `since`, or by the last sync of
+/// each repo if no duration is given. Repos without changes are left out.
+pub fn changes_since(repos: &[RepoConfig], parent_dir: &Path, since: Option<Duration>) -> Vec<PageChanges> {
+    let state = SyncState::load(parent_dir);
+    let cutoff = since.map(|since| now().saturating_sub(since.as_secs()));
+
+    repos
+        .iter()
+        .filter_map(|repo| {
+            let history = state.history.get(&repo.name())?;
+            let records = match cutoff {
+                Some(cutoff) => &history[history.partition_point(|record| record.time < cutoff)..],
+                None => &history[history.len().saturating_sub(1)..],
+            };
+            let (first, last) = (records.first()?, records.last()?);
+            let from = first.from.clone();
+
+            let mut changes = PageChanges {
+                name: repo.name(),
+                from: from.clone(),
+                to: last.to.clone(),
+                ..Default::default()
+            };
+            if let Some(from) = from
+                && let Err(err) = diff_pages(repo, &checkout_dir(repo, parent_dir), &from, &mut changes)
+            {
+                changes.error = Some(err.to_string());
+            }
+            (!changes.is_empty()).then_some(changes)
+        })
+        .collect()
+}
+
+fn diff_pages(repo: &RepoConfig, dir: &Path, from: &str, changes: &mut PageChanges) -> Result<()> {
+    let git_repo = Repository::open(dir)?;
+    let tree = |commit: &str| {
+        Oid::from_str(commit)
+            .and_then(|oid| git_repo.find_commit(oid))
+            .and_then(|commit| commit.tree())
+            // A reclone or shallow fetch can drop the old commit
+            .map_err(|_| anyhow!("Commit {} is no longer in the checkout", short_id(commit)))
+    };
+    let diff = git_repo.diff_tree_to_tree(Some(&tree(from)?), Some(&tree(&changes.to)?), None)?;
+
+    for delta in diff.deltas() {
+        let Some(path) = delta.new_file().path().or(delta.old_file().path()) else {
+            continue;
+        };
+        let Some(page) = page_name(repo, path) else {
+            continue;
+        };
+        match delta.status() {
+            Delta::Added | Delta::Copied => changes.added.push(page),
+            Delta::Deleted => changes.removed.push(page),
+            _ => changes.modified.push(page),
+        }
+    }
+
+    Ok(())
+}
+
+/// Path without `.md` of a page file in one of the page dirs of the repo.
+fn page_name(repo: &RepoConfig, path: &Path) -> Option<String> {
+    let page = path.to_str()?.strip_suffix(".md")?;
+    let dir: Vec<_> = page.split('/').collect();
+    let dir = &dir[..dir.len() - 1];
+
+    let subdirs = repo.page_subdirs();
+    let in_page_dir = if subdirs.is_empty() {
+        dir.is_empty()
+    }
+    else {
+        subdirs.iter().any(|subdir| {
+            let pattern: Vec<_> = subdir.split('/').filter(|c| !c.is_empty()).collect();
+            pattern.len() == dir.len() && pattern.iter().zip(dir).all(|(p, c)| *p == "*" || p == c)
+        })
+    };
+    in_page_dir.then(|| page.to_string())
+}
+
+fn short_id(commit: &str) -> &str {
+    &commit[..commit.len().min(7)]
+}
+
+/// Changes as markdown, a section per repo.
+pub fn changes_to_markdown(changes: &[PageChanges]) -> String {
+    if changes.is_empty() {
+        return String::from("No page changes\n");
+    }
+
+    let mut md = String::new();
+    for repo in changes {
+        match &repo.from {
+            Some(from) => md.push_str(&format!("\n**{}** {}..{}\n", repo.name, short_id(from), short_id(&repo.to))),
+            None => md.push_str(&format!("\n**{}** cloned at {}\n", repo.name, short_id(&repo.to))),
+        }
+        if let Some(error) = &repo.error {
+            md.push_str(&format!("* could not compare: {error}\n"));
+        }
+        for (label, pages) in [("added", &repo.added), ("removed", &repo.removed), ("changed", &repo.modified)] {
+            for page in pages {
+                md.push_str(&format!("* {label} `{page}`\n"));
+            }
+        }
+    }
+
+    md
+}
@@ -0,0 +1,501 @@
+/*
+    Index of every page, kept in the download dir so lookups don't have to walk all
+    the sources. `--sync` rebuilds the entries of the repos it synced, any other source
+    is rebuilt by whichever command notices it changed: the checked out commit of a
+    repo, the mtimes of the dirs of a local dir or the mtime and size of a zip file.
+*/
+
+use std::collections::HashSet;
+use std::env;
+use std::fs::{ self, File };
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+use std::time::UNIX_EPOCH;
+use anyhow::Result;
+use git2::Repository;
+use serde::{ Deserialize, Serialize };
+use zip::ZipArchive;
+use crate::config::{ RepoConfig, SourceKind };
+use crate::lock::write_atomic;
+use crate::pages::{ Discovery, PageSource, bare_repo_sources, dir_sources, zip_sources };
+
+/// File in the download dir that holds the index.
+pub const INDEX_FILE: &str = ".page_index.json";
+
+/// Platform dirs of tldr pages, pages in any other dir are taken to be for every platform.
+const TLDR_PLATFORMS: &[&str] = &[
+    "android", "cisco-ios", "common", "dos", "freebsd", "linux", "netbsd", "openbsd", "osx", "sunos", "windows",
+];
+
+/// A configured page source, in the order lookups go through them.
+pub enum Source {
+    Repo {
+        repo: RepoConfig,
+        /// Checkout dir in the download dir
+        dir: PathBuf,
+    },
+    LocalDir(PathBuf),
+    LocalArchive {
+        path: PathBuf,
+        subdirs: Vec<String>,
+    },
+}
+
+impl Source {
+    pub fn id(&self) -> String {
+        match self {
+            Source::Repo { repo, .. } => format!("repo:{}", repo.name()),
+            Source::LocalDir(dir) => format!("dir:{}", dir.display()),
+            Source::LocalArchive { path, .. } => format!("archive:{}", path.display()),
+        }
+    }
+
+    /// Pattern of the per language dirs, None for tldr's `pages.<language>`.
+    fn language_dir(&self) -> Option<&str> {
+        match self {
+            Source::Repo { repo, .. } => repo.language_dir.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn subdirs(&self) -> Vec<String> {
+        match self {
+            Source::Repo { repo, .. } => repo.page_subdirs(),
+            Source::LocalDir(_) => Vec::new(),
+            Source::LocalArchive { subdirs, .. } => subdirs.clone(),
+        }
+    }
+
+    /// Changes whenever the pages of the source might have, None if there is nothing to read.
+    fn stamp(&self, dirs: &[String]) -> Option<String> {
+        let mtime = |path: &Path| -> Option<u128> {
+            Some(fs::metadata(path).ok()?.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+        };
+        match self {
+            Source::Repo { repo, dir } if repo.kind == SourceKind::Archive => mtime(dir).map(|mtime| mtime.to_string()),
+            Source::Repo { dir, .. } => {
+                let git_repo = Repository::open(dir).ok()?;
+                let commit = git_repo.head().ok()?.peel_to_commit().ok()?;
+                Some(commit.id().to_string())
+            },
+            // A page added or removed anywhere changes the mtime of the dir it is in
+            Source::LocalDir(root) => {
+                let mtimes: Vec<_> = dirs
+                    .iter()
+                    .map(|dir| mtime(Path::new(dir)).unwrap_or(0).to_string())
+                    .collect();
+                Some(format!("{}:{}", mtime(root)?, mtimes.join(",")))
+            },
+            Source::LocalArchive { path, .. } => {
+                let len = fs::metadata(path).ok()?.len();
+                Some(format!("{}-{len}", mtime(path)?))
+            },
+        }
+    }
+
+    /// Root of the source on disk, page paths of dirs are relative to it.
+    fn root(&self) -> &Path {
+        match self {
+            Source::Repo { dir, .. } => dir,
+            Source::LocalDir(dir) => dir,
+            Source::LocalArchive { path, .. } => path,
+        }
+    }
+
+    fn page_sources(&self, discovery: &Discovery) -> Result<Vec<PageSource>> {
+        match self {
+            Source::Repo { repo, dir } if repo.bare => bare_repo_sources(dir, &repo.page_subdirs(), discovery),
+            Source::Repo { repo, dir } => Ok(dir_sources(dir, &repo.page_subdirs(), discovery)),
+            Source::LocalDir(dir) => Ok(dir_sources(dir, &[], discovery)),
+            Source::LocalArchive { path, subdirs } => zip_sources(path, subdirs, discovery),
+        }
+    }
+
+    /// Reads a page of the source by its indexed path.
+    pub fn read_page(&self, path: &str) -> Option<String> {
+        match self {
+            Source::Repo { repo, dir } if repo.bare => {
+                let git_repo = Repository::open_bare(dir).ok()?;
+                let tree = git_repo.head().ok()?.peel_to_tree().ok()?;
+                let blob = git_repo.find_blob(tree.get_path(Path::new(path)).ok()?.id()).ok()?;
+                String::from_utf8(blob.content().to_vec()).ok()
+            },
+            Source::Repo { dir, .. } | Source::LocalDir(dir) => fs::read_to_string(dir.join(path)).ok(),
+            Source::LocalArchive { path: archive, .. } => {
+                let mut archive = ZipArchive::new(File::open(archive).ok()?).ok()?;
+                let mut page = String::new();
+                archive.by_name(path).ok()?.read_to_string(&mut page).ok()?;
+                Some(page)
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PageIndex {
+    /// In the order of the configured sources
+    pub sources: Vec<IndexedSource>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IndexedSource {
+    pub id: String,
+    /// Discovery settings and subdirs the pages were found with
+    settings: String,
+    stamp: Option<String>,
+    /// Dirs of a local dir that were walked, their mtimes make up the stamp
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    dirs: Vec<String>,
+    pub pages: Vec<IndexedPage>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IndexedPage {
+    pub name: String,
+    /// Path relative to the checkout or local dir, or inside the zip or git tree
+    pub path: String,
+    /// Dir the page is in, like "linux" for `pages/linux/ls.md`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// From a tldr style `pages.<language>` dir, plain `pages` is English, or from the
+    /// `language_dir` of the repo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+impl IndexedPage {
+    fn new(path: String, language_dir: Option<&str>) -> Self {
+        let components: Vec<_> = path.split('/').collect();
+        let (file_name, dirs) = components.split_last().expect("split always yields a component");
+        let language = match language_dir {
+            // Pages outside of the language dirs are the English ones, like `pages` next to `pages.{language}`
+            Some(pattern) => pattern_language(&path, pattern).or(Some(String::from("en"))),
+            None => dirs.iter().rev().find_map(|dir| match *dir {
+                "pages" => Some(String::from("en")),
+                dir => dir.strip_prefix("pages.").map(String::from),
+            }),
+        };
+        let platform = dirs
+            .last()
+            .filter(|dir| **dir != "pages" && !dir.starts_with("pages."))
+            .map(|dir| dir.to_string());
+
+        IndexedPage {
+            name: file_name.trim_end_matches(".md").to_string(),
+            path,
+            platform,
+            language,
+        }
+    }
+}
+
+/// Language of a page path below a `language_dir` pattern like "translations/{language}".
+fn pattern_language(path: &str, pattern: &str) -> Option<String> {
+    let (prefix, suffix) = pattern.trim_matches('/').split_once("{language}")?;
+    let rest = path.strip_prefix(prefix)?;
+    let (language, _) = rest.split_once(&format!("{suffix}/"))?;
+    (!language.is_empty() && !language.contains('/')).then(|| language.to_string())
+}
+
+/// The tldr name of the platform pager runs on.
+pub fn host_platform() -> &'static str {
+    match env::consts::OS {
+        "macos" | "ios" => "osx",
+        "solaris" | "illumos" => "sunos",
+        os => os,
+    }
+}
+
+/// Order in which pages of the different platforms are picked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Platforms {
+    order: Vec<String>,
+}
+
+impl Platforms {
+    /// `order` as in `page_db.platform_order`, with "host" standing for `platform`.
+    pub fn new(platform: &str, order: &[String]) -> Self {
+        let mut platforms = Vec::new();
+        for name in order {
+            let name = if name == "host" { platform } else { name };
+            if !platforms.iter().any(|known| known == name) {
+                platforms.push(name.to_string());
+            }
+        }
+        Platforms { order: platforms }
+    }
+
+    /// Platform of a page, "common" for pages outside of platform dirs.
+    pub fn of<'a>(&self, page: &'a IndexedPage) -> &'a str {
+        match page.platform.as_deref() {
+            Some(platform) if TLDR_PLATFORMS.contains(&platform) || self.order.iter().any(|known| known == platform) => platform,
+            _ => "common",
+        }
+    }
+
+    /// Whether a page is only meant for a platform that isn't asked for.
+    pub fn is_foreign(&self, page: &IndexedPage) -> bool {
+        let platform = self.of(page);
+        platform != "common" && !self.order.iter().any(|known| known == platform)
+    }
+
+    /// Position of the platform of a page in the order, unlisted platforms come last.
+    pub fn rank(&self, page: &IndexedPage) -> usize {
+        let platform = self.of(page);
+        self.order.iter().position(|known| known == platform).unwrap_or(self.order.len())
+    }
+}
+
+/// Languages to show pages in, most preferred first and always ending with English.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Languages {
+    order: Vec<String>,
+}
+
+impl Languages {
+    /// Locales like "pt_BR.UTF-8" are preferred as "pt_BR", then as "pt".
+    pub fn new<'a>(preferred: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut order: Vec<String> = Vec::new();
+        for locale in preferred {
+            let language = locale.split(['.', '@']).next().unwrap_or(locale).trim();
+            if language.is_empty() || language == "C" || language == "POSIX" {
+                continue;
+            }
+            let base = language.split(['_', '-']).next().unwrap_or(language);
+            for language in [language, base] {
+                if !order.iter().any(|known| known == language) {
+                    order.push(language.to_string());
+                }
+            }
+        }
+        if !order.iter().any(|known| known == "en") {
+            order.push(String::from("en"));
+        }
+        Languages { order }
+    }
+
+    /// Languages from LANGUAGE and LANG, LANGUAGE is only looked at when LANG is set,
+    /// like the tldr client spec asks for.
+    pub fn from_env() -> Self {
+        let Ok(lang) = env::var("LANG") else {
+            return Languages::new([]);
+        };
+        let language = env::var("LANGUAGE").unwrap_or_default();
+        Languages::new(language.split(':').chain([lang.as_str()]))
+    }
+
+    /// Position of the language of a page in the order, None if it isn't wanted.
+    /// Pages without a language are for every language.
+    pub fn rank(&self, page: &IndexedPage) -> Option<usize> {
+        match &page.language {
+            Some(language) => self.order.iter().position(|known| known == language),
+            None => Some(0),
+        }
+    }
+
+    /// Whether a page in `language` is what was asked for, not a fallback.
+    pub fn is_preferred(&self, language: &str) -> bool {
+        let base = |language: &str| language.split(['_', '-']).next().unwrap_or(language).to_string();
+        base(language) == base(&self.order[0])
+    }
+
+    pub fn preferred(&self) -> &str {
+        &self.order[0]
+    }
+
+    pub fn all(&self) -> &[String] {
+        &self.order
+    }
+}
+
+impl PageIndex {
+    /// Loads the index of a download dir, a missing or broken file is an empty index.
+    pub fn load(download_dir: &Path) -> Self {
+        fs::read(download_dir.join(INDEX_FILE))
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, download_dir: &Path) -> Result<()> {
+        fs::create_dir_all(download_dir)?;
+        write_atomic(&download_dir.join(INDEX_FILE), serde_json::to_vec(self)?)
+    }
+
+    /// Rebuilds the sources that changed, or that are in `rebuild`, and drops the ones
+    /// that are no longer configured. Returns whether anything changed.
+    pub fn refresh(&mut self, sources: &[Source], discovery: &Discovery, rebuild: &HashSet<String>) -> bool {
+        let previous_ids: Vec<_> = self.sources.iter().map(|indexed| indexed.id.clone()).collect();
+        let mut previous: Vec<_> = self.sources.drain(..).map(Some).collect();
+        let mut changed = false;
+
+        for source in sources {
+            let id = source.id();
+            let settings = format!(
+                "{:?} {:?} {} {:?}",
+                source.subdirs(), source.language_dir(), discovery.max_depth, discovery.ignore
+            );
+            let indexed = previous
+                .iter_mut()
+                .find(|indexed| indexed.as_ref().is_some_and(|indexed| indexed.id == id))
+                .and_then(Option::take)
+                .filter(|indexed| {
+                    !rebuild.contains(&id)
+                        && indexed.settings == settings
+                        && indexed.stamp == source.stamp(&indexed.dirs)
+                });
+
+            self.sources.push(match indexed {
+                Some(indexed) => indexed,
+                None => {
+                    changed = true;
+                    index_source(source, id, settings, discovery)
+                },
+            });
+        }
+
+        changed || self.sources.iter().map(|indexed| &indexed.id).ne(previous_ids.iter())
+    }
+
+    /// Every page with this name, in the order of the sources, with the index of its source.
+    pub fn lookup<'a>(&'a self, page_name: &'a str) -> impl Iterator<Item = (usize, &'a IndexedPage)> + 'a {
+        self.sources
+            .iter()
+            .enumerate()
+            .flat_map(|(i, source)| source.pages.iter().map(move |page| (i, page)))
+            .filter(move |(_, page)| page.name == page_name)
+    }
+
+    /// Pages with this name in the order they should be shown in. Translations are
+    /// alternatives, only the best language found is kept and its pages are ordered by
+    /// platform, so a common page in German beats an English one for the platform.
+    pub fn find<'a>(&'a self, page_name: &'a str, platforms: &Platforms, languages: &Languages) -> Vec<(usize, &'a IndexedPage)> {
+        let mut found: Vec<_> = self.lookup(page_name)
+            .filter(|(_, page)| languages.rank(page).is_some())
+            .collect();
+        let language = found
+            .iter()
+            .filter(|(_, page)| page.language.is_some())
+            .min_by_key(|(_, page)| languages.rank(page))
+            .and_then(|(_, page)| page.language.clone());
+        found.retain(|(_, page)| page.language.is_none() || page.language == language);
+        found.sort_by_key(|(_, page)| platforms.rank(page));
+        found
+    }
+
+    /// Names of all pages, sorted and without duplicates.
+    pub fn page_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.sources
+            .iter()
+            .flat_map(|source| source.pages.iter().map(|page| page.name.as_str()))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Up to `limit` page names close to `page_name`, best first.
+    pub fn suggestions(&self, page_name: &str, limit: usize) -> Vec<&str> {
+        self.ranked(page_name)
+            .into_iter()
+            .take(limit)
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    /// The closest page name, if no other name is just as close.
+    pub fn best_match(&self, page_name: &str) -> Option<&str> {
+        match self.ranked(page_name).as_slice() {
+            [(best, name), rest @ ..] if rest.first().is_none_or(|(next, _)| next > best) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Names that start with `page_name` come first, then the ones within a few typos
+    /// of it, closest first.
+    fn ranked(&self, page_name: &str) -> Vec<((usize, usize), &str)> {
+        let query = page_name.to_lowercase();
+        let max_distance = (query.chars().count() / 4).max(1);
+        let mut ranked: Vec<_> = self.page_names()
+            .into_iter()
+            .filter_map(|name| {
+                let lower = name.to_lowercase();
+                let distance = edit_distance(&query, &lower);
+                if lower.starts_with(&query) {
+                    Some(((0, distance), name))
+                }
+                else {
+                    (distance <= max_distance).then_some(((1, distance), name))
+                }
+            })
+            .collect();
+        ranked.sort();
+        ranked
+    }
+}
+
+/// Levenshtein distance that counts swapping two neighbouring characters as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<_> = a.chars().collect();
+    let b: Vec<_> = b.chars().collect();
+    // rows[i][j] is the distance between the first i chars of a and the first j of b
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+
+    rows[a.len()][b.len()]
+}
+
+fn index_source(source: &Source, id: String, settings: String, discovery: &Discovery) -> IndexedSource {
+    let page_sources = source.page_sources(discovery).unwrap_or_else(|err| {
+        eprintln!("Could not index pages of {id:?}: {err}");
+        Vec::new()
+    });
+
+    let mut seen = HashSet::new();
+    let mut pages = Vec::new();
+    let mut dirs = Vec::new();
+    for page_source in page_sources {
+        let (paths, walked) = page_source.list_pages(discovery);
+        for path in paths {
+            // Dir sources list absolute paths
+            let path = match Path::new(&path).strip_prefix(source.root()) {
+                Ok(rel) => rel.to_string_lossy().to_string(),
+                Err(_) => path,
+            };
+            if seen.insert(path.clone()) {
+                pages.push(IndexedPage::new(path, source.language_dir()));
+            }
+        }
+        dirs.extend(walked.into_iter().map(|dir| dir.to_string_lossy().to_string()));
+    }
+
+    let dirs = match source {
+        Source::LocalDir(_) => dirs,
+        _ => Vec::new(),
+    };
+    IndexedSource {
+        id,
+        settings,
+        stamp: source.stamp(&dirs),
+        dirs,
+        pages,
+    }
+}
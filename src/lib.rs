@@ -0,0 +1,10 @@
+pub mod changes;
+pub mod config;
+pub mod index;
+pub mod lock;
+pub mod manifest;
+pub mod pages;
+pub mod state;
+pub mod sync;
+
+pub use sync::sync_git_repos;
@@ -0,0 +1,118 @@
+use std::fs::{ self, File, OpenOptions, TryLockError };
+use std::io::{ Read, Write };
+use std::path::Path;
+use serde::{ Deserialize, Serialize };
+use anyhow::{ Result, anyhow };
+use crate::state::now;
+
+/// Held for the whole duration of a sync so two syncs never work on the same download dir.
+pub const SYNC_LOCK_FILE: &str = ".sync.lock";
+
+/// Held shared by lookups and exclusively while a sync swaps checkouts into place.
+pub const SNAPSHOT_LOCK_FILE: &str = ".snapshot.lock";
+
+/// Who holds the sync lock, written into the lock file for the error message of the next sync.
+#[derive(Debug, Deserialize, Serialize)]
+struct LockHolder {
+    pid: u32,
+    host: String,
+    started: u64,
+}
+
+/// Exclusive lock on a download dir for the duration of a sync.
+///
+/// The lock itself is an OS file lock, so it goes away with the process if a sync crashes.
+/// The holder info a crashed sync leaves behind in the file is just overwritten by the next one.
+pub struct SyncLock {
+    file: File,
+}
+
+impl SyncLock {
+    pub fn acquire(download_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(download_dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(download_dir.join(SYNC_LOCK_FILE))?;
+
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                file.read_to_string(&mut holder)?;
+                let holder = match toml::from_str::<LockHolder>(&holder) {
+                    Ok(holder) => format!(
+                        "pid {} on {}, started {}s ago",
+                        holder.pid,
+                        holder.host,
+                        now().saturating_sub(holder.started)
+                    ),
+                    Err(_) => String::from("unknown process"),
+                };
+                return Err(anyhow!("Another sync of {:?} is already running ({holder})", download_dir));
+            },
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+
+        let holder = LockHolder {
+            pid: std::process::id(),
+            host: hostname(),
+            started: now(),
+        };
+        file.set_len(0)?;
+        file.write_all(toml::to_string(&holder)?.as_bytes())?;
+
+        Ok(SyncLock { file })
+    }
+}
+
+impl Drop for SyncLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Locks the checkouts of a download dir against being swapped out, shared for readers and
+/// exclusive for a sync that is about to swap. The lock is released when the file is dropped.
+pub fn lock_snapshot(download_dir: &Path, exclusive: bool) -> Result<File> {
+    fs::create_dir_all(download_dir)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(download_dir.join(SNAPSHOT_LOCK_FILE))?;
+
+    if exclusive {
+        file.lock()?;
+    }
+    else {
+        file.lock_shared()?;
+    }
+
+    Ok(file)
+}
+
+/// Writes a file next to `path` and renames it over it, so a lookup never reads a half
+/// written file. The temp name has the pid in it so two processes never share one.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}.tmp", std::process::id()));
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp_path);
+    })?;
+    Ok(())
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| String::from("unknown host"))
+}
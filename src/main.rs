@@ -1,26 +1,26 @@
-use clap::{ Parser, ValueEnum };
-use std::thread;
+use clap::{ ArgGroup, Parser, ValueEnum };
 use termimad::crossterm::style::{Attributes, Color};
-use indicatif::{ ProgressBar, ProgressStyle, MultiProgress };
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 use std::env;
 use termimad::{ Alignment, CompoundStyle, LineStyle, ListItemsIndentationMode, MadSkin, ScrollBarStyle, StyledChar, TableBorderChars };
 use std::fs;
-use regex::Regex;
-use std::path::{ Path, PathBuf };
-use git2::{ Repository, FetchOptions, RemoteCallbacks };
-use std::process::{Command, Stdio};
-use serde::{ Deserialize, Serialize };
+use std::path::Path;
 use anyhow::{ Result, anyhow };
 use path_absolutize::Absolutize;
+use std::process::{ Command, Stdio };
 use std::time::Duration;
-use std::sync::{ Arc, Mutex };
-use portable_pty::{CommandBuilder, PtySize, native_pty_system};
-use std::io::{self, BufRead, BufReader, Read, stdout, Write};
-
-static NAME: &'static str = env!("CARGO_PKG_NAME");
-static VERSION: &'static str = env!("CARGO_PKG_VERSION");
-static ABOUT_MSG: &'static str = r#"
+use pager::state::{ SyncState, now };
+use pager::lock::lock_snapshot;
+use pager::index::{ Languages, PageIndex, Platforms, Source, host_platform };
+use pager::pages::Discovery;
+use pager::changes::{ PageChanges, changes_since, changes_to_markdown };
+use pager::config::{ Config, PageDb, RepoConfig, Style, parse_duration, validate_config };
+use pager::manifest::{ LOCK_FILE, PagesLock };
+use pager::sync::{ PlannedAction, ProgressMode, SyncAction, SyncOptions, checkout_commit, checkout_dir, plan_sync, prunable_checkouts, prune_checkouts, select_repos, sync_git_repos };
+
+static NAME: &str = env!("CARGO_PKG_NAME");
+static VERSION: &str = env!("CARGO_PKG_VERSION");
+static ABOUT_MSG: &str = r#"
 
 Art by Hayley Jane Wakenshaw
 
@@ -34,47 +34,6 @@ Art by Hayley Jane Wakenshaw
 
 type MarkdownPage = String;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Config {
-    page_db: PageDb,
-    style: Style,
-    default_flags: DefaultFlags,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct PageDb {
-    git_repos: Vec<Vec<String>>,
-    git_download_dir: String,
-    local_dirs: Vec<String>,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct Style {
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct DefaultFlags {
-
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            page_db: PageDb {
-                git_repos: Vec::new(),
-                git_download_dir: String::from("./online_pages"),
-                local_dirs: Vec::new(),
-            },
-            style: Style {
-
-            },
-            default_flags: DefaultFlags {
-
-            },
-        }
-    }
-}
-
 #[derive(Clone, Debug, ValueEnum)]
 enum Shell {
     Bash,
@@ -103,14 +62,39 @@ OPTIONS:
     disable_version_flag = true,
     arg_required_else_help = true,
 )]
+#[command(group(ArgGroup::new("maintenance").args(["sync", "update_lock", "prune"]).multiple(true)))]
 struct Args {
     /// Show documentation 
     #[arg(long)]
     documentation: bool,
 
-    /// Sync online page-db against git repos
+    /// Sync online page-db against git repos (only the named repos if any are given)
+    #[arg(long, num_args = 0.., value_name = "REPO")]
+    sync: Option<Vec<String>>,
+
+    /// Check out the commits recorded in pages.lock instead of the latest ones
+    #[arg(long, requires = "sync")]
+    locked: bool,
+
+    /// Sync to the latest commits and record them in pages.lock (only the named repos if any are given)
+    #[arg(long, num_args = 0.., value_name = "REPO", conflicts_with = "sync")]
+    update_lock: Option<Vec<String>>,
+
+    /// Show the pages the last sync of each repo changed, or every change synced in SINCE (like "7d")
+    #[arg(long, num_args = 0..=1, value_name = "SINCE")]
+    changes: Option<Option<String>>,
+
+    /// Remove checkouts pager made for repos that are no longer in the config
     #[arg(long)]
-    sync: bool,
+    prune: bool,
+
+    /// Show what --sync/--update-lock/--prune would do without changing anything
+    #[arg(long, requires = "maintenance")]
+    dry_run: bool,
+
+    /// How --sync shows its progress, bars need stderr to be a terminal
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = ProgressMode::Auto)]
+    progress: ProgressMode,
 
     /// Generate shell completions 
     #[arg(long)]
@@ -132,6 +116,22 @@ struct Args {
     #[arg(short, long)]
     interactive: bool,
 
+    /// Platform to prefer pages of, like linux, osx or windows (defaults to the one pager runs on)
+    #[arg(short, long, value_name = "PLATFORM")]
+    platform: Option<String>,
+
+    /// Languages to prefer pages in, like "de" or "pt_BR,pt" (defaults to LANGUAGE and LANG, then English)
+    #[arg(short = 'L', long, value_name = "LANGUAGE", value_delimiter = ',')]
+    language: Option<Vec<String>>,
+
+    /// Show the closest page when none has this name, as long as only one is that close
+    #[arg(long)]
+    closest: bool,
+
+    /// List the names of all pages
+    #[arg(long)]
+    list: bool,
+
     /// Name of page[s] to show
     page_name: Option<String>,
 
@@ -146,205 +146,30 @@ struct Args {
 
 fn show_page(page: &str, skin: &MadSkin, _args: &Args) {
     skin.print_text(page);
-    println!("");
-}
-
-fn sync_git_repos(git_urls: &Vec<Vec<String>>, parent_dir: &Path) -> Result<()> {
-    fs::create_dir_all(parent_dir)?;
-    let multi_progress = MultiProgress::new();
-    multi_progress.println("Cloning online page repos from git")?;
-    let mut threads = vec![];
-    for entry in git_urls.iter().rev() {
-        let url = &entry[0];
-
-        let repo_name = url
-            .split('/')
-            .last()
-            .unwrap_or("unknown")
-            .trim_end_matches(".git");
-
-        let target_dir = parent_dir.join(repo_name);
-        if target_dir.exists() {
-            fs::remove_dir_all(&target_dir).unwrap();
-        }
-
-        let progress_bar = multi_progress.add(ProgressBar::new_spinner());
-        let url_clone = url.clone();
-        threads.push(thread::spawn(move || {
-            thread::sleep(Duration::from_millis(500));
-            clone_repo(&url_clone, &target_dir, &progress_bar.clone()).unwrap();
-        }));
-    }
-    for thread in threads {
-        let _ = thread.join();
-    }
-
-    Ok(())
-}
-
-enum CloneState {
-    ReceivingObjects,
-    ResolvingDeltas,
-    UpdatingFiles,
-    Finished,
-}
-
-impl CloneState {
-    fn new() -> Self {
-        CloneState::ReceivingObjects
-    }
-
-    fn next(&self) -> Self {
-        match self {
-            CloneState::ReceivingObjects => CloneState::ResolvingDeltas,
-            CloneState::ResolvingDeltas => CloneState::UpdatingFiles,
-            CloneState::UpdatingFiles => CloneState::Finished,
-            CloneState::Finished => CloneState::Finished,
-        }
-    }
-
-    fn text(&self) -> String {
-        match self {
-            CloneState::ReceivingObjects => String::from("Receiving objects"),
-            CloneState::ResolvingDeltas => String::from("Resolving deltas"),
-            CloneState::UpdatingFiles => String::from("Updating files"),
-            CloneState::Finished => String::from("Finished"),
-        }
-    }
-
-    fn style(&self) -> ProgressStyle {
-        let template = match self {
-            CloneState::ReceivingObjects => "[{bar:40.cyan/blue}] {pos}/{len} {msg}",
-            CloneState::ResolvingDeltas => "[{bar:40.yellow/cyan}] {pos}/{len} {msg}",
-            CloneState::UpdatingFiles => "[{bar:40.green/yellow}] {pos}/{len} {msg}",
-            CloneState::Finished => "[{bar:40.green/yellow}] {pos}/{len} {msg}",
-        };
-
-        let progress_chars = match self {
-            CloneState::ReceivingObjects => "##-",
-            CloneState::ResolvingDeltas => "=>#",
-            CloneState::UpdatingFiles => "->=",
-            CloneState::Finished => "->=",
-        };
-
-        ProgressStyle::default_bar()
-            .template(template)
-            .unwrap()
-            .progress_chars(progress_chars)
-    }
-}
-
-fn clone_repo(url: &str, dest: &Path, pb: &ProgressBar) -> anyhow::Result<()> {
-    /*
-        It would have been less hacky to use libgit2 for this but it was 10-100x slower 
-        which made cloning larger repos such as tldr really tedious imo.
-        So instead I opted to use the native git binary and manually parse the progress 
-        output with some regex to drive indicatif progress bars.
-
-        Needed to do some fiddling with the output from git since it iterates the progress
-        report using carriage returns. Solution based on: https://askubuntu.com/a/990280 
-    */
-
-    let mut cmd = Command::new("git");
-    cmd.arg("clone")
-        .arg("--progress")
-        .arg(url)
-        .arg(dest)
-        .stderr(Stdio::piped());
-
-    let mut child = cmd.spawn()?;
-    let mut stderr = child.stderr.take().expect("Failed to capture stderr");
-    let mut reader = BufReader::new(stderr);
-
-
-    pb.enable_steady_tick(Duration::from_millis(100));
-    let repo_name = dest.components().last().expect("This cannot be empty").as_os_str();
-    pb.set_message(format!("Beginning cloning for {:?}", repo_name));
-
-    let mut buffer = Vec::new();
-    let mut temp = [0u8; 1024];
-    let mut clone_state = CloneState::new();
-
-    while let Ok(n) = reader.read(&mut temp) {
-        if n == 0 { break; }
-        buffer.extend_from_slice(&temp[..n]);
-        // Replace \r with \n
-        while let Some(pos) = buffer.iter().position(|&b| b == b'\r') {
-            buffer[pos] = b'\n';
-        }
-
-        // Process complete lines
-        while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
-            let line = String::from_utf8_lossy(&buffer[..pos]).to_string();
-            buffer.drain(..=pos);
-
-            let pattern = &format!(r"{}:\s*(?:\d+%)?\s*\((\d+)/(\d+)\)", clone_state.text());
-
-            let progress_regex = Regex::new(pattern).unwrap();
-
-            let mut last_received = 0;
-            if let Some(caps) = progress_regex.captures(&line) {
-                let received: u64 = caps[1].parse().unwrap_or(0);
-                let total: u64 = caps[2].parse().unwrap_or(0);
-                pb.set_length(total);
-                pb.set_position(received);
-                if received > last_received {
-                    pb.set_style(clone_state.style());
-                    pb.set_message(format!("Cloning {:?}: {}", repo_name, clone_state.text()));
-                }
-
-                if received == total {
-                    clone_state = clone_state.next();
-                    last_received = 0;
-                }
-            }
-        }
-    }
-
-    let status = child.wait()?;
-    if status.success() {
-        pb.set_style(clone_state.style());
-        pb.finish_with_message(format!("✅ Finished cloning {:?}", repo_name));
-    } else {
-        pb.set_style(clone_state.style());
-        pb.finish_with_message(format!("❌ Failed cloning {:?}", repo_name));
-    }
-
-    Ok(())
+    println!();
 }
 
-fn get_page<I>(page_name: &str, db_iter: I, args: &Args) -> MarkdownPage 
+/// The first page, or all of them combined with --combine. None if there are none.
+fn get_page<I>(pages: I, args: &Args) -> Option<MarkdownPage>
 where 
-    I: Iterator<Item = PathBuf>,
+    I: Iterator<Item = MarkdownPage>,
 {
-    let mut pages = db_iter
-        .filter_map(|dir| {
-        let page_path = dir.join(format!("{page_name}.md"));
-
-        if page_path.exists() && page_path.is_file() {
-            fs::read_to_string(&page_path).ok()
-        } else {
-            None
-        }
-    }).peekable();
-
-    if pages.peek().is_none() {
-        format!("No result found for: {page_name}")
+    let mut pages = pages;
+    if args.combine {
+        pages.reduce(|s1, s2| format!("{s1}\n{s2}"))
     }
     else {
-        if args.combine {
-            pages.reduce(|s1, s2| format!("{s1}\n{s2}"))
-                .expect("I check for this in the if above")
-        }
-        else {
-            pages.next()
-                .expect("I check for this in the if above")
-        }
+        pages.next()
     }
 }
 
-fn validate_config(_config: &Config) -> Vec<String> {
-    Vec::new()
+fn page_not_found(page_name: &str, suggestions: &[&str]) -> anyhow::Error {
+    if suggestions.is_empty() {
+        anyhow!("No page named {page_name:?}")
+    }
+    else {
+        anyhow!("No page named {page_name:?}, did you mean {}?", suggestions.join(", "))
+    }
 }
 
 fn get_skin(_style: &Style) -> MadSkin {
@@ -383,26 +208,108 @@ fn get_skin(_style: &Style) -> MadSkin {
     }
 }
 
-fn get_online_hashmap(git_repos: &Vec<Vec<String>>) -> HashMap<String, String> {
-    let online_hashmap: HashMap<_, _> = git_repos
+/// Page sources in lookup order. Only configured repos are looked at, leftover
+/// checkouts of removed repos are ignored.
+fn configured_sources(page_db: &PageDb, repos: &[RepoConfig], download_dir: &Path, config_dir: &Path) -> Vec<Source> {
+    let repos = repos
         .iter()
-        .filter_map(|x| {
-            let repo_name = x[0]
-                .split('/')
-                .last()
-                .unwrap_or("unknown")
-                .trim_end_matches(".git");
-
-            if x.len() == 2 {
-                Some((String::from(repo_name), x[1].clone()))
-            }
-            else {
+        .map(|repo| Source::Repo {
+            dir: checkout_dir(repo, download_dir),
+            repo: repo.clone(),
+        });
+    let local_dirs = page_db.local_dirs
+        .iter()
+        .filter_map(|dir| Path::new(dir)
+            .absolutize_from(config_dir).ok() // Imo it should be fine to throw away bad paths
+            .map(|abs| Source::LocalDir(abs.to_path_buf()))
+        );
+    let local_archives = page_db.local_archives
+        .iter()
+        .filter_map(|archive| match Path::new(&archive.path).absolutize_from(config_dir) {
+            Ok(path) => Some(Source::LocalArchive {
+                path: path.to_path_buf(),
+                subdirs: archive.subdirs.clone(),
+            }),
+            Err(err) => {
+                eprintln!("Could not read page archive {:?}: {err}", archive.path);
                 None
-            }
-        })
-        .collect();
+            },
+        });
+
+    repos.chain(local_dirs).chain(local_archives).collect()
+}
+
+/// Brings the page index up to date with the sources, `rebuild` lists the ids of sources
+/// to index again even if they look unchanged.
+fn refresh_index(sources: &[Source], download_dir: &Path, page_db: &PageDb, rebuild: &HashSet<String>) -> PageIndex {
+    let mut index = PageIndex::load(download_dir);
+    if index.refresh(sources, &Discovery::from(page_db), rebuild)
+        && let Err(err) = index.save(download_dir)
+    {
+        eprintln!("Could not save the page index: {err}");
+    }
+    index
+}
+
+fn print_changes(changes: &[PageChanges], skin: &MadSkin, json: bool) {
+    if !json {
+        skin.print_text(&changes_to_markdown(changes));
+        return;
+    }
+
+    for repo in changes {
+        println!("{}", serde_json::json!({
+            "event": "changes",
+            "repo": repo.name,
+            "from": repo.from,
+            "to": repo.to,
+            "added": repo.added,
+            "removed": repo.removed,
+            "changed": repo.modified,
+            "error": repo.error,
+        }));
+    }
+}
+
+/// Starts `pager --sync` for the stale repos as a detached process, so the lookup
+/// never has to wait for the network.
+fn refresh_stale_repos(repos: &[RepoConfig], download_dir: &Path, ttl: Duration) -> Result<()> {
+    let state = SyncState::load(download_dir);
+    let stale = state.stale_repos(repos, ttl);
+    if stale.is_empty() {
+        return Ok(());
+    }
+
+    // Repos last synced with --locked stay on their locked commits
+    let (locked, unlocked): (Vec<_>, Vec<_>) = stale
+        .iter()
+        .partition(|name| state.locked.contains(*name));
+    for (names, locked) in [(locked, true), (unlocked, false)] {
+        if names.is_empty() {
+            continue;
+        }
+        let mut cmd = Command::new(env::current_exe()?);
+        cmd.arg("--sync")
+            .args(names)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if locked {
+            cmd.arg("--locked");
+        }
+        // Keep a Ctrl-C meant for the lookup from killing the refresh
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        cmd.spawn()?;
+    }
 
-    online_hashmap
+    eprintln!("Online pages for {} are out of date, refreshing them in the background", stale.join(", "));
+    let now = now();
+    SyncState::update(download_dir, |state| {
+        for name in stale {
+            state.last_refresh.insert(name, now);
+        }
+    })
 }
 
 fn main() -> Result<()> {
@@ -425,7 +332,7 @@ fn main() -> Result<()> {
         Parse and validate config 
     */
     let toml_str = fs::read_to_string(&config_path)?;
-    let config = toml::from_str(&toml_str)?;
+    let config: Config = toml::from_str(&toml_str)?;
     let config_errors = validate_config(&config);
 
     if !config_errors.is_empty() {
@@ -436,12 +343,10 @@ fn main() -> Result<()> {
     }
 
     let PageDb {
-        git_repos: repos, 
         git_download_dir,
-        local_dirs,
+        ..
     } = &config.page_db;
 
-
     let config_dir = config_path
         .parent()
         .ok_or(anyhow!("'Aint no way, aint no fucking way' -Future"))?;
@@ -454,60 +359,206 @@ fn main() -> Result<()> {
         Parse cli args 
     */
     let args = Args::parse();
-    if args.sync {
-        sync_git_repos(repos, &download_dir)?;
+    let languages = match &args.language {
+        Some(languages) => Languages::new(languages.iter().map(String::as_str)),
+        None => Languages::from_env(),
+    };
+    let repos = &config.page_db.repos();
+    if args.sync.is_some() || args.update_lock.is_some() || args.prune {
+        let lock_path = config_dir.join(LOCK_FILE);
+        let mut pages_lock = PagesLock::load(&lock_path)?;
+        let sync_names = args.sync.as_ref().or(args.update_lock.as_ref());
+        let mut selected = match sync_names {
+            Some(names) => select_repos(repos, names)?,
+            None => Vec::new(),
+        };
+        if args.locked {
+            selected = pages_lock.locked_repos(&selected)?;
+        }
+
+        if args.dry_run {
+            let mut plan = plan_sync(&selected, &download_dir);
+            if args.prune {
+                plan.extend(prunable_checkouts(repos, &download_dir)
+                    .into_iter()
+                    .map(|name| (name, PlannedAction::Prune))
+                );
+            }
+            for (name, action) in plan {
+                println!("Would {action} {name:?}");
+            }
+            return Ok(());
+        }
+
+        let options = SyncOptions {
+            progress: args.progress,
+            ..SyncOptions::from(&config.page_db)
+        };
+        let json = args.progress == ProgressMode::Json;
+        let summary = match sync_names {
+            Some(_) => Some(sync_git_repos(&selected, &download_dir, &options)?),
+            None => None,
+        };
+
+        /*
+            Record the synced commits, a plain sync only adds repos that aren't locked yet
+        */
+        if let Some(summary) = &summary
+            && !args.locked
+        {
+            let previous = pages_lock.clone();
+            for (repo, report) in selected.iter().zip(&summary.reports) {
+                if report.action == SyncAction::Failed
+                    || (args.update_lock.is_none() && pages_lock.commit(repo).is_some())
+                {
+                    continue;
+                }
+                if let Some(commit) = checkout_commit(repo, &download_dir) {
+                    pages_lock.set(repo, commit);
+                }
+            }
+            pages_lock.retain_configured(repos);
+            if pages_lock != previous {
+                pages_lock.save(&lock_path)?;
+            }
+        }
+        if let Some(summary) = &summary {
+            // Auto-refresh keeps to the lock for repos synced with --locked
+            SyncState::update(&download_dir, |state| {
+                for (repo, report) in selected.iter().zip(&summary.reports) {
+                    if report.action == SyncAction::Failed {
+                        continue;
+                    }
+                    if args.locked {
+                        state.locked.insert(repo.name());
+                    }
+                    else {
+                        state.locked.remove(&repo.name());
+                    }
+                }
+            })?;
+
+            let synced = selected
+                .iter()
+                .map(|repo| Source::Repo { repo: repo.clone(), dir: checkout_dir(repo, &download_dir) }.id())
+                .collect();
+            refresh_index(&configured_sources(&config.page_db, repos, &download_dir, config_dir), &download_dir, &config.page_db, &synced);
+        }
+        // Every repo already got a finished event in JSON mode
+        if let Some(summary) = &summary
+            && !json
+        {
+            get_skin(&config.style).print_text(&summary.to_markdown());
+        }
+        if let Some(summary) = &summary {
+            // A failed repo has no new sync to report, only the one before it
+            let synced: Vec<_> = selected
+                .iter()
+                .zip(&summary.reports)
+                .filter(|(_, report)| report.action != SyncAction::Failed)
+                .map(|(repo, _)| repo.clone())
+                .collect();
+            print_changes(&changes_since(&synced, &download_dir, None), &get_skin(&config.style), json);
+        }
+
+        // Only on request, a repo commented out for a while keeps its checkout through syncs
+        let pruned = if args.prune { prune_checkouts(repos, &download_dir)? } else { Vec::new() };
+        for name in pruned {
+            if json {
+                println!("{}", serde_json::json!({ "event": "pruned", "repo": name }));
+            }
+            else {
+                println!("Pruned {name:?}");
+            }
+        }
+
+        if let Some(summary) = summary
+            && summary.failed() > 0
+        {
+            return Err(anyhow!("{} of {} repos failed to sync", summary.failed(), summary.reports.len()));
+        }
+        return Ok(());
+    }
+
+    if let Some(since) = &args.changes {
+        let since = match since {
+            Some(since) => Some(parse_duration(since)
+                .ok_or(anyhow!("--changes takes a duration like \"12h\" or \"7d\", not {since:?}"))?),
+            None => None,
+        };
+        let json = args.progress == ProgressMode::Json;
+        print_changes(&changes_since(repos, &download_dir, since), &get_skin(&config.style), json);
+        return Ok(());
+    }
+
+    /*
+        Kick off a background refresh if the online pages are older than auto_sync_after
+    */
+    if let (Some(ttl), Some(_)) = (config.page_db.auto_sync_after(), &args.page_name)
+        && let Err(err) = refresh_stale_repos(repos, &download_dir, ttl)
+    {
+        eprintln!("Could not start a background refresh of the online pages: {err}");
+    }
+
+    /*
+        List the names of all pages
+    */
+    if args.list {
+        let _snapshot = lock_snapshot(&download_dir, false).ok();
+        let sources = configured_sources(&config.page_db, repos, &download_dir, config_dir);
+        let index = refresh_index(&sources, &download_dir, &config.page_db, &HashSet::new());
+        for name in index.page_names() {
+            println!("{name}");
+        }
         return Ok(());
     }
 
     /* 
         Lookup and show page 
     */
-    let online_hashmap = get_online_hashmap(repos);
     if let Some(page_name) = &args.page_name {
-        let db_iter = fs::read_dir(download_dir)?
-            .flat_map(|entry| {
-                match entry {
-                    Ok(e) => {
-                        if e.path().is_dir() {
-                            let file_name = e.file_name();
-                            let dir_name = file_name.to_str().unwrap_or("");
-                            let mut path = e.path();
-                            if let Some(subdir) = online_hashmap.get(dir_name) {
-                                for c in subdir.split("/") {
-                                    if c == "*" {
-                                        // This is some ugly ass fuckshit
-                                        let mut paths = Vec::new();
-                                        for entry in fs::read_dir(path).unwrap() {
-                                            let e = entry.unwrap();
-                                            let metadata = e.metadata().unwrap();
-                                            if metadata.is_dir() {
-                                                paths.push(e.path());
-                                            }
-                                        }
-                                        return paths;
-                                    }
-                                    else {
-                                        path = path.join(c);
-                                    }
-                                }
-                            }
-                            vec![path]
-                        }
-                        else {
-                            Vec::new()
-                        }
-                    },
-                    _ => Vec::new(),
-                }
-            }).chain(local_dirs
+        // Keeps a running sync from swapping checkouts while the page is looked up
+        let _snapshot = lock_snapshot(&download_dir, false).ok();
+        let sources = configured_sources(&config.page_db, repos, &download_dir, config_dir);
+        let index = refresh_index(&sources, &download_dir, &config.page_db, &HashSet::new());
+        let platform = args.platform.as_deref().unwrap_or(host_platform());
+        let platforms = Platforms::new(platform, &config.page_db.platform_order);
+        let find = |name: &str| {
+            let found: Vec<_> = index
+                .find(name, &platforms, &languages)
+                .into_iter()
+                .filter_map(|(i, page)| sources[i].read_page(&page.path).map(|text| (page, text)))
+                .collect();
+            // Pages of other platforms are only shown when there's nothing better, say so when they are
+            let shown = if args.combine { found.len() } else { found.len().min(1) };
+            let mut foreign: Vec<_> = found[..shown]
                 .iter()
-                .filter_map(|dir| Path::new(dir)
-                    .absolutize_from(&config_dir).ok() // Imo it should be fine to throw away bad paths
-                    .map(|abs| abs.to_path_buf())
-                )
-            );
+                .filter(|(page, _)| platforms.is_foreign(page))
+                .map(|(page, _)| platforms.of(page))
+                .collect();
+            foreign.sort();
+            foreign.dedup();
+            for other in foreign {
+                eprintln!("Showing the {other} page of {name:?}, it may not apply to {platform}");
+            }
+            if let Some(language) = found.iter().find_map(|(page, _)| page.language.as_deref())
+                && !languages.is_preferred(language)
+            {
+                eprintln!("There is no {} version of {name:?}, showing the {language} one", languages.preferred());
+            }
+            get_page(found.into_iter().map(|(_, text)| text), &args)
+        };
 
-        let page = get_page(&page_name, db_iter, &args);
+        let page = match find(page_name) {
+            Some(page) => page,
+            None => match index.best_match(page_name).filter(|_| args.closest) {
+                Some(best) => {
+                    eprintln!("There is no page named {page_name:?}, showing {best:?} instead");
+                    find(best).ok_or(page_not_found(best, &[]))?
+                },
+                None => return Err(page_not_found(page_name, &index.suggestions(page_name, 5))),
+            },
+        };
         let skin = get_skin(&config.style);
         show_page(&page, &skin, &args);
     }
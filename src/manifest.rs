@@ -0,0 +1,100 @@
+/*
+    pages.lock records the commit every repo was synced to, so a team can share it
+    next to the config and `--sync --locked` gives everyone the same pages. Only
+    `--update-lock` moves the recorded commits, a plain `--sync` just adds repos
+    the lock doesn't know about yet.
+*/
+
+use std::fs;
+use std::path::Path;
+use anyhow::{ Result, anyhow };
+use serde::{ Deserialize, Serialize };
+use crate::config::{ RepoConfig, SourceKind };
+use crate::lock::write_atomic;
+
+/// File next to the config that holds the locked commits.
+pub const LOCK_FILE: &str = "pages.lock";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PagesLock {
+    #[serde(default, rename = "repo", skip_serializing_if = "Vec::is_empty")]
+    pub repos: Vec<LockedRepo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LockedRepo {
+    pub name: String,
+    pub url: String,
+    pub commit: String,
+}
+
+impl PagesLock {
+    /// A missing lock is empty, a broken one is an error so it never gets overwritten.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(PagesLock::default());
+        }
+        toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| anyhow!("Could not parse {path:?}: {err}"))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_atomic(path, toml::to_string_pretty(self)?)
+    }
+
+    /// Locked commit of a repo, only if it was locked for the same url.
+    pub fn commit(&self, repo: &RepoConfig) -> Option<&str> {
+        let name = repo.name();
+        self.repos
+            .iter()
+            .find(|locked| locked.name == name && locked.url == repo.url)
+            .map(|locked| locked.commit.as_str())
+    }
+
+    pub fn set(&mut self, repo: &RepoConfig, commit: String) {
+        let name = repo.name();
+        self.repos.retain(|locked| locked.name != name);
+        self.repos.push(LockedRepo {
+            name,
+            url: repo.url.clone(),
+            commit,
+        });
+        self.repos.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Drops the repos that are no longer in the config.
+    pub fn retain_configured(&mut self, repos: &[RepoConfig]) {
+        self.repos.retain(|locked| repos.iter().any(|repo| repo.name() == locked.name));
+    }
+
+    /// The repos pinned to their locked commits. Archives have no commit and are synced as is.
+    pub fn locked_repos(&self, repos: &[RepoConfig]) -> Result<Vec<RepoConfig>> {
+        let mut unlocked = Vec::new();
+        let locked = repos
+            .iter()
+            .map(|repo| {
+                if repo.kind == SourceKind::Archive {
+                    return repo.clone();
+                }
+                match self.commit(repo) {
+                    Some(commit) => RepoConfig {
+                        git_ref: Some(commit.to_string()),
+                        ..repo.clone()
+                    },
+                    None => {
+                        unlocked.push(repo.name());
+                        repo.clone()
+                    },
+                }
+            })
+            .collect();
+
+        if !unlocked.is_empty() {
+            return Err(anyhow!(
+                "{LOCK_FILE} has no commit for {}, run pager --update-lock to add them",
+                unlocked.join(", ")
+            ));
+        }
+        Ok(locked)
+    }
+}
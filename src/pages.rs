@@ -0,0 +1,324 @@
+/*
+    Where the page index finds pages. Most sources are plain dirs, zip archives listed
+    as `local_archive` are listed in place without extracting them and bare clones are
+    listed from their HEAD tree. Pages are looked for below
+    every source root up to `page_depth` dirs deep, shallower pages come first.
+*/
+
+use std::collections::{ BTreeSet, HashSet };
+use std::fs::{ self, File };
+use std::io::ErrorKind;
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
+use anyhow::Result;
+use git2::{ ObjectType, Repository, TreeWalkMode, TreeWalkResult };
+use zip::ZipArchive;
+use crate::config::{ Config, PageDb };
+
+/// How deep pages are looked for below a source root and what is skipped, see the
+/// `page_*` settings of `PageDb`.
+#[derive(Clone, Debug)]
+pub struct Discovery {
+    pub max_depth: usize,
+    pub ignore: Vec<String>,
+}
+
+impl From<&PageDb> for Discovery {
+    fn from(page_db: &PageDb) -> Self {
+        Discovery {
+            max_depth: page_db.page_depth,
+            ignore: page_db.page_ignore.clone(),
+        }
+    }
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery::from(&Config::default().page_db)
+    }
+}
+
+impl Discovery {
+    /// Whether anything on the path below a source root is ignored.
+    pub fn ignored(&self, rel_path: &str) -> bool {
+        let components: Vec<_> = rel_path.split('/').collect();
+        (1..=components.len()).any(|i| {
+            let prefix = components[..i].join("/");
+            self.ignore.iter().any(|pattern| {
+                if pattern.contains('/') {
+                    glob_match(pattern.trim_matches('/'), &prefix)
+                }
+                else {
+                    glob_match(pattern, components[i - 1])
+                }
+            })
+        })
+    }
+
+    /// Paths of the pages below `dir` in a list of file paths, shallowest first.
+    fn matching<'a>(&self, paths: &'a [String], dir: &str) -> Vec<&'a str> {
+        let mut found: Vec<_> = paths
+            .iter()
+            .filter_map(|path| {
+                let rel = if dir.is_empty() { path.as_str() } else { path.strip_prefix(dir)?.strip_prefix('/')? };
+                let depth = rel.matches('/').count();
+                let name = rel.rsplit('/').next().unwrap_or_default();
+                let matches = name.ends_with(".md")
+                    && depth <= self.max_depth
+                    && !self.ignored(rel);
+                matches.then_some((depth, path.as_str()))
+            })
+            .collect();
+        found.sort();
+        found.into_iter().map(|(_, path)| path).collect()
+    }
+}
+
+fn is_glob(component: &str) -> bool {
+    component.contains(['*', '?'])
+}
+
+/// Shell like glob where `*` matches any run of characters and `?` a single one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some(('*', rest)) => (0..=text.len()).any(|skip| matches(rest, &text[skip..])),
+            Some(('?', rest)) => !text.is_empty() && matches(rest, &text[1..]),
+            Some((c, rest)) => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+pub enum PageSource {
+    Dir(PathBuf),
+    /// Dir inside a zip archive, "" for the root of the archive
+    Zip {
+        entries: Rc<Vec<String>>,
+        dir: String,
+    },
+    /// Dir inside the tree of a bare clone, "" for the root of the tree
+    Git {
+        entries: Rc<Vec<String>>,
+        dir: String,
+    },
+}
+
+impl PageSource {
+    /// Paths of every page below the source, shallowest first, along with the dirs that
+    /// were walked to find them. Paths are absolute for dirs and entry paths otherwise.
+    pub fn list_pages(&self, discovery: &Discovery) -> (Vec<String>, Vec<PathBuf>) {
+        match self {
+            PageSource::Dir(dir) => {
+                let (pages, dirs) = walk_dir(dir, discovery);
+                let pages = pages.into_iter().map(|page| page.to_string_lossy().to_string()).collect();
+                (pages, dirs)
+            },
+            PageSource::Zip { entries, dir, .. } | PageSource::Git { entries, dir, .. } => {
+                let pages = discovery.matching(entries, dir).into_iter().map(String::from).collect();
+                (pages, Vec::new())
+            },
+        }
+    }
+}
+
+/// Walks `root` a level at a time so shallower pages are found first, returns the
+/// pages and the dirs that were read. Dirs are only entered once and symlinks to the
+/// root or above it are skipped, so links can't send the walk in circles.
+fn walk_dir(root: &Path, discovery: &Discovery) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut found = Vec::new();
+    let mut walked = Vec::new();
+    let mut visited = HashSet::new();
+    let mut level = vec![(root.to_path_buf(), String::new())];
+    let Ok(canonical_root) = fs::canonicalize(root) else {
+        return (found, walked);
+    };
+
+    for depth in 0..=discovery.max_depth {
+        let mut next_level = Vec::new();
+        for (dir, rel) in level {
+            let canonical = match fs::canonicalize(&dir) {
+                Ok(canonical) => canonical,
+                Err(err) => {
+                    warn_unreadable(&dir, &err);
+                    continue;
+                },
+            };
+            let loops_back = depth > 0 && canonical_root.starts_with(&canonical);
+            if loops_back || !visited.insert(canonical) {
+                continue;
+            }
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warn_unreadable(&dir, &err);
+                    continue;
+                },
+            };
+            walked.push(dir.clone());
+
+            let mut entries: Vec<_> = entries
+                .filter_map(|entry| entry
+                    .inspect_err(|err| eprintln!("Could not read an entry of page dir {dir:?}: {err}"))
+                    .ok()
+                )
+                .collect();
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let rel = if rel.is_empty() { name.clone() } else { format!("{rel}/{name}") };
+                if discovery.ignored(&rel) {
+                    continue;
+                }
+
+                let path = entry.path();
+                // is_dir follows symlinks, so linked dirs are searched too
+                if path.is_dir() {
+                    if depth < discovery.max_depth {
+                        next_level.push((path, rel));
+                    }
+                }
+                else if name.ends_with(".md") {
+                    found.push(path);
+                }
+            }
+        }
+        level = next_level;
+    }
+
+    (found, walked)
+}
+
+fn warn_unreadable(dir: &Path, err: &std::io::Error) {
+    // Page dirs of a repo that doesn't have them (yet) are fine
+    if err.kind() != ErrorKind::NotFound {
+        eprintln!("Could not read page dir {dir:?}: {err}");
+    }
+}
+
+/// A source for every dir of a checkout matching the subdirs, `*` in a subdir matches
+/// every dir on that level that isn't ignored and `pages.*` the ones starting with
+/// `pages.`. Dirs that can't be listed are skipped with a warning.
+pub fn dir_sources(checkout: &Path, subdirs: &[String], discovery: &Discovery) -> Vec<PageSource> {
+    if subdirs.is_empty() {
+        return vec![PageSource::Dir(checkout.to_path_buf())];
+    }
+
+    let mut dirs = Vec::new();
+    for subdir in subdirs {
+        let mut paths = vec![checkout.to_path_buf()];
+        for component in subdir.split('/').filter(|c| !c.is_empty()) {
+            if !is_glob(component) {
+                paths = paths.into_iter().map(|path| path.join(component)).collect();
+                continue;
+            }
+
+            paths = paths
+                .into_iter()
+                .flat_map(|path| match fs::read_dir(&path) {
+                    Ok(entries) => {
+                        let mut children: Vec<_> = entries
+                            .filter_map(|entry| entry.ok())
+                            .map(|entry| entry.path())
+                            .filter(|child| child.is_dir())
+                            .filter(|child| child
+                                .file_name()
+                                .is_some_and(|name| glob_match(component, &name.to_string_lossy()))
+                            )
+                            .filter(|child| !child
+                                .strip_prefix(checkout)
+                                .is_ok_and(|rel| discovery.ignored(&rel.to_string_lossy()))
+                            )
+                            .collect();
+                        children.sort();
+                        children
+                    },
+                    Err(err) => {
+                        warn_unreadable(&path, &err);
+                        Vec::new()
+                    },
+                })
+                .collect();
+        }
+        // A language glob like `pages.*/*` can match the English subdirs again
+        for path in paths {
+            if !dirs.contains(&path) {
+                dirs.push(path);
+            }
+        }
+    }
+
+    dirs.into_iter().map(PageSource::Dir).collect()
+}
+
+/// Dirs matching `subdirs` that contain at least one of the file paths, globs in a
+/// subdir match the dirs on that level that aren't ignored.
+fn matching_dirs(paths: &[String], subdirs: &[String], discovery: &Discovery) -> BTreeSet<String> {
+    let mut dirs = BTreeSet::new();
+    if subdirs.is_empty() {
+        dirs.insert(String::new());
+    }
+    for subdir in subdirs {
+        let pattern: Vec<_> = subdir.split('/').filter(|c| !c.is_empty()).collect();
+        for name in paths {
+            let components: Vec<_> = name.split('/').collect();
+            // The matched dir has to contain the entry, so the entry needs a component past it
+            let matches = components.len() > pattern.len()
+                && pattern.iter().zip(&components).all(|(p, c)| glob_match(p, c));
+            if !matches {
+                continue;
+            }
+            let dir = components[..pattern.len()].join("/");
+            if !(pattern.iter().any(|p| is_glob(p)) && discovery.ignored(&dir)) {
+                dirs.insert(dir);
+            }
+        }
+    }
+    dirs
+}
+
+/// A source for every page dir of a zip archive.
+pub fn zip_sources(path: &Path, subdirs: &[String], discovery: &Discovery) -> Result<Vec<PageSource>> {
+    let archive = ZipArchive::new(File::open(path)?)?;
+    let entries: Vec<_> = archive.file_names()
+        .filter_map(|name| name.ok())
+        .filter(|name| !name.ends_with('/'))
+        .map(String::from)
+        .collect();
+    let dirs = matching_dirs(&entries, subdirs, discovery);
+
+    let entries = Rc::new(entries);
+    Ok(dirs
+        .into_iter()
+        .map(|dir| PageSource::Zip {
+            entries: Rc::clone(&entries),
+            dir,
+        })
+        .collect())
+}
+
+/// A source for every page dir in the HEAD tree of a bare clone.
+pub fn bare_repo_sources(path: &Path, subdirs: &[String], discovery: &Discovery) -> Result<Vec<PageSource>> {
+    let repo = Repository::open_bare(path)?;
+    let mut entries = Vec::new();
+    repo.head()?.peel_to_tree()?.walk(TreeWalkMode::PreOrder, |parent, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            entries.push(format!("{parent}{}", entry.name().unwrap_or_default()));
+        }
+        TreeWalkResult::Ok
+    })?;
+    let dirs = matching_dirs(&entries, subdirs, discovery);
+
+    let entries = Rc::new(entries);
+    Ok(dirs
+        .into_iter()
+        .map(|dir| PageSource::Git {
+            entries: Rc::clone(&entries),
+            dir,
+        })
+        .collect())
+}
@@ -0,0 +1,101 @@
+use std::collections::{ BTreeMap, BTreeSet };
+use std::fs;
+use std::path::Path;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use serde::{ Deserialize, Serialize };
+use anyhow::Result;
+use crate::config::RepoConfig;
+use crate::lock::{ lock_snapshot, write_atomic };
+
+/// File in the download dir that remembers when each repo was last synced.
+pub const STATE_FILE: &str = ".sync_state.toml";
+
+/// How long to wait before starting another background refresh of a repo that is still stale.
+const REFRESH_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Syncs remembered per repo for `--changes`.
+const MAX_HISTORY: usize = 100;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SyncState {
+    /// Unix timestamp of the last successful sync per repo name
+    #[serde(default)]
+    pub last_sync: BTreeMap<String, u64>,
+    /// Unix timestamp of the last automatic refresh that was started per repo name
+    #[serde(default)]
+    pub last_refresh: BTreeMap<String, u64>,
+    /// Commits of the last successful git syncs per repo name, oldest first
+    #[serde(default)]
+    pub history: BTreeMap<String, Vec<SyncRecord>>,
+    /// Repos whose last successful sync checked out the commit in pages.lock
+    #[serde(default)]
+    pub locked: BTreeSet<String>,
+}
+
+/// Commit a repo moved from and to in one sync.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SyncRecord {
+    /// Unix timestamp of the sync
+    pub time: u64,
+    /// None when the sync cloned the repo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    pub to: String,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl SyncState {
+    /// Loads the state of a download dir, a missing or broken file is treated as never synced.
+    pub fn load(download_dir: &Path) -> Self {
+        fs::read_to_string(download_dir.join(STATE_FILE))
+            .ok()
+            .and_then(|toml_str| toml::from_str(&toml_str).ok())
+            .unwrap_or_default()
+    }
+
+    /// Load, change and save the state while holding the snapshot lock, so a sync
+    /// and a lookup starting a refresh don't overwrite each other's changes.
+    pub fn update<F>(download_dir: &Path, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut SyncState),
+    {
+        let _lock = lock_snapshot(download_dir, true)?;
+        let mut state = SyncState::load(download_dir);
+        f(&mut state);
+        state.save(download_dir)
+    }
+
+    pub fn save(&self, download_dir: &Path) -> Result<()> {
+        write_atomic(&download_dir.join(STATE_FILE), toml::to_string_pretty(self)?)
+    }
+
+    pub fn record_sync(&mut self, name: &str, record: SyncRecord) {
+        let history = self.history.entry(name.to_string()).or_default();
+        history.push(record);
+        if history.len() > MAX_HISTORY {
+            history.drain(..history.len() - MAX_HISTORY);
+        }
+    }
+
+    /// Names of the repos whose last sync is older than `ttl` and that don't have a refresh
+    /// started recently.
+    pub fn stale_repos(&self, repos: &[RepoConfig], ttl: Duration) -> Vec<String> {
+        let now = now();
+        repos
+            .iter()
+            .map(|repo| repo.name())
+            .filter(|name| {
+                let synced = self.last_sync.get(name).copied().unwrap_or(0);
+                let refreshed = self.last_refresh.get(name).copied().unwrap_or(0);
+                now.saturating_sub(synced) > ttl.as_secs()
+                    && now.saturating_sub(refreshed) > REFRESH_RETRY_INTERVAL.as_secs()
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,910 @@
+use std::thread;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use git2::{ Oid, Repository };
+use std::process::{ Command, Stdio };
+use anyhow::{ Result, anyhow };
+use serde::Serialize;
+use std::time::{ Duration, Instant };
+use std::io::{ BufReader, Read };
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::mpsc::{ self, RecvTimeoutError };
+use crate::config::{ PageDb, RepoConfig, SourceKind, SyncBackend, repo_name, repo_path };
+use crate::state::{ SyncRecord, SyncState, now };
+use crate::lock::{ SyncLock, lock_snapshot };
+use crate::pages::{ Discovery, bare_repo_sources, dir_sources };
+
+mod archive;
+mod auth;
+mod libgit2;
+mod output;
+pub mod progress;
+
+pub use output::ProgressMode;
+use output::{ RepoProgress, SyncOutput };
+use progress::{ GitLine, ProgressParser, git_command };
+
+/// Dir inside the download dir where repos are cloned/updated before being swapped into place.
+pub const STAGING_DIR: &str = ".staging";
+
+/// Fetch refspec of bare clones, branches are fetched straight into refs/heads.
+const BARE_REFSPEC: &str = "+refs/heads/*:refs/heads/*";
+
+/// Outcome of syncing a single repo, one row of the summary table.
+#[derive(Debug)]
+pub struct RepoReport {
+    pub name: String,
+    pub action: SyncAction,
+    /// Short hash of the commit that is checked out after the sync
+    pub commit: Option<String>,
+    pub page_count: usize,
+    pub duration: Duration,
+    pub error: Option<String>,
+    /// What git printed to stderr, apart from progress, when the sync failed
+    pub stderr: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncAction {
+    Cloned,
+    Updated,
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct SyncSummary {
+    pub reports: Vec<RepoReport>,
+}
+
+/// Error for a git invocation that exited non-zero, keeps the stderr around for the summary.
+#[derive(Debug)]
+pub struct GitError {
+    pub message: String,
+    pub stderr: String,
+}
+
+impl std::fmt::Display for GitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for GitError {}
+
+impl SyncSummary {
+    pub fn failed(&self) -> usize {
+        self.reports
+            .iter()
+            .filter(|report| report.action == SyncAction::Failed)
+            .count()
+    }
+
+    /// Summary as a markdown table, followed by the git output of every failed repo.
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::from("|repo|status|commit|pages|duration|\n|-|-|-|-:|-:|\n");
+        for report in &self.reports {
+            let status = match report.action {
+                SyncAction::Cloned => "✅ cloned",
+                SyncAction::Updated => "✅ updated",
+                SyncAction::Failed => "❌ failed",
+            };
+            md.push_str(&format!(
+                "|{}|{}|{}|{}|{:.1}s|\n",
+                report.name,
+                status,
+                report.commit.as_deref().unwrap_or("-"),
+                report.page_count,
+                report.duration.as_secs_f64(),
+            ));
+        }
+
+        for report in self.reports.iter().filter(|report| report.action == SyncAction::Failed) {
+            md.push_str(&format!("\n**{}**: {}\n", report.name, report.error.as_deref().unwrap_or("")));
+            if !report.stderr.trim().is_empty() {
+                md.push_str(&format!("```\n{}\n```\n", report.stderr.trim_end()));
+            }
+        }
+
+        md
+    }
+}
+
+/// How a sync is run, see the `sync_*` settings of `PageDb`.
+#[derive(Clone, Debug)]
+pub struct SyncOptions {
+    /// Max number of repos synced at the same time
+    pub jobs: usize,
+    /// Time a single attempt at syncing a repo may take before git is killed
+    pub timeout: Duration,
+    /// Extra attempts for a repo that failed, with exponential backoff in between
+    pub retries: u32,
+    pub backend: SyncBackend,
+    pub progress: ProgressMode,
+    /// How pages are counted for the report, the same way lookups find them
+    pub discovery: Discovery,
+}
+
+impl From<&PageDb> for SyncOptions {
+    fn from(page_db: &PageDb) -> Self {
+        SyncOptions {
+            jobs: page_db.sync_jobs,
+            timeout: Duration::from_secs(page_db.sync_timeout),
+            retries: page_db.sync_retries,
+            backend: page_db.sync_backend,
+            progress: ProgressMode::default(),
+            discovery: Discovery::from(page_db),
+        }
+    }
+}
+
+/// State shared by every git invocation of one sync attempt.
+struct SyncTask<'a> {
+    progress: &'a RepoProgress,
+    deadline: Instant,
+    backend: SyncBackend,
+}
+
+pub fn sync_git_repos(repos: &[RepoConfig], parent_dir: &Path, options: &SyncOptions) -> Result<SyncSummary> {
+    let _lock = SyncLock::acquire(parent_dir)?;
+    fs::create_dir_all(parent_dir.join(STAGING_DIR))?;
+    let output = SyncOutput::new(options.progress);
+    output.notice("Syncing online page repos from git")?;
+    let backend = match options.backend {
+        SyncBackend::Auto if git_available() => SyncBackend::Git,
+        SyncBackend::Auto => {
+            output.notice("git was not found on the PATH, syncing with libgit2 instead")?;
+            SyncBackend::Libgit2
+        },
+        backend => backend,
+    };
+    let options = &SyncOptions { backend, ..options.clone() };
+
+    let progress: Vec<_> = repos
+        .iter()
+        .map(|repo| output.repo(repo.name()))
+        .collect();
+
+    // Fixed pool of workers that each grab the next repo that hasn't been started
+    let next_repo = AtomicUsize::new(0);
+    let reports = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..options.jobs.min(repos.len()) {
+            scope.spawn(|| {
+                loop {
+                    let i = next_repo.fetch_add(1, Ordering::SeqCst);
+                    let (Some(repo), Some(repo_progress)) = (repos.get(i), progress.get(i)) else {
+                        break;
+                    };
+
+                    let start = Instant::now();
+                    let previous_commit = checkout_commit(repo, parent_dir);
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        sync_repo_with_retries(repo, parent_dir, repo_progress, options)
+                    }))
+                    .unwrap_or_else(|_| Err(anyhow!("Sync thread panicked")));
+                    if let Err(err) = &result {
+                        repo_progress.fail(format!("❌ Failed syncing {:?}: {err}", repo.name()));
+                    }
+
+                    let report = repo_report(repo, &checkout_dir(repo, parent_dir), result, start.elapsed(), &options.discovery);
+                    repo_progress.report(&report);
+                    reports.lock().unwrap().push((i, report, previous_commit));
+                }
+            });
+        }
+    });
+
+    let mut reports = reports.into_inner().unwrap();
+    reports.sort_by_key(|(i, _, _)| *i);
+
+    let now = now();
+    SyncState::update(parent_dir, |state| {
+        for (i, report, previous_commit) in &reports {
+            if report.action == SyncAction::Failed {
+                continue;
+            }
+            state.last_sync.insert(report.name.clone(), now);
+            if let Some(commit) = checkout_commit(&repos[*i], parent_dir) {
+                state.record_sync(&report.name, SyncRecord {
+                    time: now,
+                    from: previous_commit.clone(),
+                    to: commit,
+                });
+            }
+        }
+    })?;
+
+    Ok(SyncSummary {
+        reports: reports.into_iter().map(|(_, report, _)| report).collect(),
+    })
+}
+
+/// Picks the repos named on the command line, or all of them if no names are given.
+/// A repo can be named by its full name or just the last segment of its url.
+pub fn select_repos(repos: &[RepoConfig], names: &[String]) -> Result<Vec<RepoConfig>> {
+    if names.is_empty() {
+        return Ok(repos.to_vec());
+    }
+
+    let matches = |repo: &RepoConfig, name: &str| repo.name() == name || repo_name(&repo.url) == name;
+    let unknown: Vec<_> = names
+        .iter()
+        .filter(|name| !repos.iter().any(|repo| matches(repo, name)))
+        .map(|name| format!("{name:?}"))
+        .collect();
+    if !unknown.is_empty() {
+        let known: Vec<_> = repos.iter().map(|repo| repo.name()).collect();
+        return Err(anyhow!(
+            "No configured repo named {}, the configured repos are: {}",
+            unknown.join(", "),
+            known.join(", ")
+        ));
+    }
+
+    Ok(repos
+        .iter()
+        .filter(|repo| names.iter().any(|name| matches(repo, name)))
+        .cloned()
+        .collect())
+}
+
+/// What a sync would do to a repo, used by `--dry-run`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlannedAction {
+    Clone,
+    Update,
+    /// The existing dir isn't a usable checkout of the repo and gets replaced
+    Reclone,
+    /// Checkout of a repo that is no longer configured
+    Prune,
+}
+
+impl std::fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlannedAction::Clone => write!(f, "clone"),
+            PlannedAction::Update => write!(f, "update"),
+            PlannedAction::Reclone => write!(f, "replace with a fresh clone"),
+            PlannedAction::Prune => write!(f, "prune"),
+        }
+    }
+}
+
+/// Works out what a sync of `repos` would do without touching the disk.
+pub fn plan_sync(repos: &[RepoConfig], parent_dir: &Path) -> Vec<(String, PlannedAction)> {
+    repos
+        .iter()
+        .map(|repo| {
+            let target_dir = checkout_dir(repo, parent_dir);
+            let action = if is_valid_checkout(&target_dir, repo) {
+                PlannedAction::Update
+            }
+            else if target_dir.exists() {
+                PlannedAction::Reclone
+            }
+            else {
+                PlannedAction::Clone
+            };
+            (repo.name(), action)
+        })
+        .collect()
+}
+
+/// Where the checkout of a repo lives. Checkouts used to be named after the last url
+/// segment only, one of those is used until the next sync moves it.
+pub fn checkout_dir(repo: &RepoConfig, parent_dir: &Path) -> PathBuf {
+    let dir = parent_dir.join(repo.name());
+    match legacy_checkout(repo, parent_dir) {
+        Some(legacy_dir) if !dir.exists() => legacy_dir,
+        _ => dir,
+    }
+}
+
+/// Full hash of the commit checked out for a repo, None for archives and missing checkouts.
+pub fn checkout_commit(repo: &RepoConfig, parent_dir: &Path) -> Option<String> {
+    let git_repo = Repository::open(checkout_dir(repo, parent_dir)).ok()?;
+    let commit = git_repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+fn legacy_checkout(repo: &RepoConfig, parent_dir: &Path) -> Option<PathBuf> {
+    if repo.name.is_some() {
+        return None;
+    }
+
+    let legacy_dir = parent_dir.join(repo_name(&repo.url));
+    is_valid_checkout(&legacy_dir, repo).then_some(legacy_dir)
+}
+
+/// Checkouts in the download dir that don't belong to any configured repo, as paths
+/// relative to it. Only dirs pager can tell it made count, see `is_own_checkout`, anything
+/// else in the download dir is left alone. Hidden entries are pager's own bookkeeping.
+pub fn prunable_checkouts(repos: &[RepoConfig], parent_dir: &Path) -> Vec<String> {
+    fn walk(dir: &Path, rel: &str, checkouts: &[String], state: &SyncState, prunable: &mut Vec<String>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        // file_type doesn't follow symlinks, links are never checkouts or walked into
+        for entry in entries.filter_map(|entry| entry.ok()).filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir())) {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if rel.is_empty() && name.starts_with('.') {
+                continue;
+            }
+
+            let path = if rel.is_empty() { name } else { format!("{rel}/{name}") };
+            if checkouts.contains(&path) {
+                continue;
+            }
+            if is_own_checkout(&entry.path(), &path, state) {
+                prunable.push(path);
+            }
+            // Host and owner dirs, or something that isn't pager's at all
+            else if Repository::open(entry.path()).is_err() {
+                walk(&entry.path(), &path, checkouts, state, prunable);
+            }
+        }
+    }
+
+    let checkouts: Vec<_> = repos
+        .iter()
+        .filter_map(|repo| {
+            let dir = checkout_dir(repo, parent_dir);
+            let rel = dir.strip_prefix(parent_dir).ok()?;
+            rel.to_str().map(String::from)
+        })
+        .collect();
+
+    let mut prunable = Vec::new();
+    walk(parent_dir, "", &checkouts, &SyncState::load(parent_dir), &mut prunable);
+    prunable.sort();
+    prunable
+}
+
+/// Whether pager made a dir of the download dir: an extracted archive, or a clone that
+/// pager synced under this name or that is named after its origin url.
+fn is_own_checkout(dir: &Path, rel: &str, state: &SyncState) -> bool {
+    if dir.join(archive::ARCHIVE_META).is_file() {
+        return true;
+    }
+
+    let Ok(git_repo) = Repository::open(dir) else {
+        return false;
+    };
+    let Some(url) = git_repo.find_remote("origin").ok().and_then(|remote| remote.url().map(String::from)) else {
+        return false;
+    };
+    state.last_sync.contains_key(rel) || repo_path(&url) == rel
+}
+
+/// Removes the checkouts of repos that are no longer configured, returns their names.
+pub fn prune_checkouts(repos: &[RepoConfig], parent_dir: &Path) -> Result<Vec<String>> {
+    let _lock = SyncLock::acquire(parent_dir)?;
+    let prunable = prunable_checkouts(repos, parent_dir);
+    for name in &prunable {
+        let _snapshot = lock_snapshot(parent_dir, true)?;
+        fs::remove_dir_all(parent_dir.join(name))?;
+        // Host and owner dirs that are left empty, remove_dir fails on the others
+        let mut parent = Path::new(name).parent();
+        while let Some(dir) = parent.filter(|dir| !dir.as_os_str().is_empty()) {
+            if fs::remove_dir(parent_dir.join(dir)).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+
+    SyncState::update(parent_dir, |state| {
+        let configured = |name: &String| repos.iter().any(|repo| repo.name() == *name);
+        state.last_sync.retain(|name, _| configured(name));
+        state.last_refresh.retain(|name, _| configured(name));
+        state.history.retain(|name, _| configured(name));
+        state.locked.retain(|name| configured(name));
+    })?;
+
+    Ok(prunable)
+}
+
+fn sync_repo_with_retries(repo: &RepoConfig, parent_dir: &Path, progress: &RepoProgress, options: &SyncOptions) -> Result<SyncAction> {
+    let mut attempt = 0;
+    loop {
+        let task = SyncTask {
+            progress,
+            deadline: Instant::now() + options.timeout,
+            backend: options.backend,
+        };
+
+        match sync_repo(repo, parent_dir, &task) {
+            Err(err) if attempt < options.retries => {
+                attempt += 1;
+                let backoff = Duration::from_secs(1 << (attempt - 1).min(6));
+                progress.message(format!(
+                    "Retrying {:?} in {}s ({attempt}/{}): {err}",
+                    repo.name(),
+                    backoff.as_secs(),
+                    options.retries,
+                ));
+                thread::sleep(backoff);
+            },
+            result => return result,
+        }
+    }
+}
+
+fn repo_report(repo: &RepoConfig, dir: &Path, result: Result<SyncAction>, duration: Duration, discovery: &Discovery) -> RepoReport {
+    // On failure the previous checkout is still in place, so its commit and pages are what's served
+    let commit = Repository::open(dir)
+        .ok()
+        .and_then(|git_repo| {
+            let commit = git_repo.head().ok()?.peel_to_commit().ok()?;
+            let short_id = commit.as_object().short_id().ok()?;
+            short_id.as_str().map(String::from)
+        });
+
+    let (action, error, stderr) = match result {
+        Ok(action) => (action, None, String::new()),
+        Err(err) => {
+            let stderr = err
+                .downcast_ref::<GitError>()
+                .map(|git_err| git_err.stderr.clone())
+                .unwrap_or_default();
+            (SyncAction::Failed, Some(err.to_string()), stderr)
+        },
+    };
+
+    RepoReport {
+        name: repo.name(),
+        action,
+        commit,
+        page_count: count_pages(repo, dir, discovery),
+        duration,
+        error,
+        stderr,
+    }
+}
+
+/// Counts the pages of a checkout or bare clone, found the same way lookups find them.
+fn count_pages(repo: &RepoConfig, dir: &Path, discovery: &Discovery) -> usize {
+    let subdirs = repo.page_subdirs();
+    let sources = if repo.bare {
+        bare_repo_sources(dir, &subdirs, discovery).unwrap_or_default()
+    }
+    else {
+        dir_sources(dir, &subdirs, discovery)
+    };
+
+    sources
+        .iter()
+        .flat_map(|source| source.list_pages(discovery).0)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+type SyncFn = fn(&RepoConfig, &Path, &SyncTask) -> Result<()>;
+
+/// Clones or updates a repo in the staging dir and only replaces the live checkout
+/// once git is done, so a failed or interrupted sync keeps the previous pages around.
+fn sync_repo(repo: &RepoConfig, parent_dir: &Path, task: &SyncTask) -> Result<SyncAction> {
+    let target_dir = parent_dir.join(repo.name());
+    let staging_dir = parent_dir.join(STAGING_DIR).join(repo.name());
+    let backup_dir = parent_dir.join(STAGING_DIR).join(format!("{}.old", repo.name()));
+    if let Some(parent) = staging_dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Move a checkout from before repos were namespaced by host and owner
+    if !target_dir.exists()
+        && let Some(legacy_dir) = legacy_checkout(repo, parent_dir)
+    {
+        let _lock = lock_snapshot(parent_dir, true)?;
+        if let Some(parent) = target_dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(legacy_dir, &target_dir)?;
+    }
+
+    // Leftovers from an interrupted sync
+    if !target_dir.exists() && backup_dir.exists() {
+        fs::rename(&backup_dir, &target_dir)?;
+    }
+    for dir in [&staging_dir, &backup_dir] {
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+    }
+
+    let (clone, update) = match task.backend {
+        SyncBackend::Libgit2 => (libgit2::clone_repo as SyncFn, libgit2::update_repo as SyncFn),
+        _ => (clone_repo as SyncFn, update_repo as SyncFn),
+    };
+    let result = if repo.kind == SourceKind::Archive {
+        archive::sync_archive(repo, &target_dir, &staging_dir, task)
+    }
+    else if is_valid_checkout(&target_dir, repo) {
+        stage_checkout(&target_dir, &staging_dir)
+            .and_then(|_| update(repo, &staging_dir, task))
+            .map(|_| Some(SyncAction::Updated))
+    }
+    else {
+        // New repo or a broken/foreign checkout, start over from a full clone
+        clone(repo, &staging_dir, task)
+            .map(|_| Some(SyncAction::Cloned))
+    };
+
+    let action = match result {
+        Ok(Some(action)) => action,
+        // Archive that didn't change, the current pages stay
+        Ok(None) => return Ok(SyncAction::Updated),
+        Err(err) => {
+            let _ = fs::remove_dir_all(&staging_dir);
+            return Err(err);
+        },
+    };
+
+    {
+        // Lookups hold the snapshot lock shared, so they never see the checkout half swapped
+        let _lock = lock_snapshot(parent_dir, true)?;
+        if target_dir.exists() {
+            fs::rename(&target_dir, &backup_dir)?;
+        }
+        else if let Some(parent) = target_dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&staging_dir, &target_dir)?;
+    }
+    if backup_dir.exists() {
+        fs::remove_dir_all(&backup_dir)?;
+    }
+
+    Ok(action)
+}
+
+/// Copies a checkout to the staging dir to update it there. Git never changes an object
+/// once it is written, so the object store, most of a checkout, is hardlinked instead.
+fn stage_checkout(src: &Path, dest: &Path) -> Result<()> {
+    let git_dir = src.join(".git");
+    let objects_dir = if git_dir.is_dir() { git_dir } else { src.to_path_buf() }.join("objects");
+    copy_dir_all(src, dest, &objects_dir)
+}
+
+/// Copies a dir, files below `link_below` are hardlinked where the filesystem allows it.
+fn copy_dir_all(src: &Path, dest: &Path, link_below: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let dest_path = dest.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_dir_all(&entry.path(), &dest_path, link_below)?;
+        }
+        else if file_type.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &dest_path)?;
+            #[cfg(not(unix))]
+            fs::copy(entry.path(), &dest_path)?;
+        }
+        else if entry.path().starts_with(link_below) && fs::hard_link(entry.path(), &dest_path).is_ok() {
+            continue;
+        }
+        else {
+            fs::copy(entry.path(), &dest_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A checkout can be updated in place if libgit2 can open it, is bare when the repo
+/// is configured as bare, HEAD resolves
+/// and origin still points at the configured url. Unpinned repos also have to be on a
+/// branch or know the default branch to go back to, a pin or `--locked` leaves HEAD
+/// detached. Archives only need to have been extracted from the configured url.
+fn is_valid_checkout(dir: &Path, repo_config: &RepoConfig) -> bool {
+    if repo_config.kind == SourceKind::Archive {
+        return archive::is_valid_extract(dir, repo_config);
+    }
+
+    let Ok(repo) = Repository::open(dir) else {
+        return false;
+    };
+
+    if repo.is_bare() != repo_config.bare || repo.head().is_err() {
+        return false;
+    }
+
+    if repo_config.git_ref.is_none()
+        && repo.head_detached().unwrap_or(true)
+        && (repo.is_bare() || default_branch(&repo).is_none())
+    {
+        return false;
+    }
+
+    repo.find_remote("origin")
+        .map(|remote| remote.url() == Some(repo_config.url.as_str()))
+        .unwrap_or(false)
+}
+
+/// Branch origin/HEAD points at, the one an unpinned checkout follows.
+pub(super) fn default_branch(git_repo: &Repository) -> Option<String> {
+    git_repo.find_reference("refs/remotes/origin/HEAD")
+        .ok()?
+        .symbolic_target()?
+        .strip_prefix("refs/remotes/origin/")
+        .map(String::from)
+}
+
+/// Resolves a configured branch, tag or commit against the fetched refs of a repo.
+/// Both backends go through this so a pin means the same commit whichever one syncs.
+pub(super) fn resolve_ref(git_repo: &Repository, git_ref: &str) -> Option<Oid> {
+    // Branches only exist as remote tracking refs after a clone/fetch
+    [format!("origin/{git_ref}"), git_ref.to_string()]
+        .iter()
+        .find_map(|spec| git_repo.revparse_single(spec).ok()?.peel_to_commit().ok())
+        .map(|commit| commit.id())
+}
+
+fn git_available() -> bool {
+    Command::new("git")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Git command run in a checkout and logged in as configured, since with a blob filter
+/// even a checkout or merge can go back to the remote for missing blobs.
+fn git_in(repo: &RepoConfig, dir: &Path) -> Result<Command> {
+    let mut cmd = git_command();
+    cmd.arg("-C").arg(dir);
+    auth::configure_git(&mut cmd, repo)?;
+    Ok(cmd)
+}
+
+/// Detaches HEAD at the pinned commit, or moves the branch HEAD points at in a bare clone.
+fn checkout_pin(repo: &RepoConfig, git_ref: &str, dest: &Path, task: &SyncTask) -> Result<()> {
+    let repo_name = repo.name();
+    let git_repo = Repository::open(dest)?;
+    let commit = match resolve_ref(&git_repo, git_ref) {
+        Some(commit) => commit.to_string(),
+        // A shallow clone only has the default branch tip, so the pin has to be fetched explicitly
+        None if repo.depth.is_some() => {
+            let mut fetch = git_in(repo, dest)?;
+            fetch.arg("fetch")
+                .arg("--progress")
+                .arg(format!("--depth={}", repo.depth.unwrap_or(1)))
+                .arg("origin")
+                .arg(git_ref);
+            run_git(fetch, task, &format!("Fetching {git_ref:?} for {repo_name:?}"))?;
+            String::from("FETCH_HEAD")
+        },
+        None => return Err(anyhow!("Could not find ref {git_ref:?} in {:?}", git_repo.path())),
+    };
+
+    let mut checkout = git_in(repo, dest)?;
+    if repo.bare {
+        // Nothing to check out, the branch HEAD points at only says which tree pages are
+        // read from. The next fetch moves it back, so HEAD never has to be detached
+        checkout.arg("update-ref")
+            .arg("HEAD");
+    }
+    else {
+        checkout.arg("checkout")
+            .arg("--progress")
+            .arg("--detach");
+    }
+    checkout.arg(commit);
+
+    run_git(checkout, task, &format!("Checking out {git_ref:?} in {repo_name:?}"))
+}
+
+/// Limits the working tree to the page subdirs, or restores the full tree if sparse got turned off.
+fn apply_sparse_checkout(repo: &RepoConfig, dest: &Path, task: &SyncTask) -> Result<()> {
+    let mut cmd = git_in(repo, dest)?;
+    cmd.arg("sparse-checkout");
+    match repo.sparse_dirs() {
+        Some(dirs) => {
+            cmd.arg("set").args(dirs);
+        },
+        None => {
+            let sparse_enabled = Repository::open(dest)?
+                .config()?
+                .get_bool("core.sparseCheckout")
+                .unwrap_or(false);
+            if !sparse_enabled {
+                return Ok(());
+            }
+            cmd.arg("disable");
+        },
+    }
+
+    run_git(cmd, task, &format!("Updating sparse checkout of {:?}", repo.name()))
+}
+
+fn clone_repo(repo: &RepoConfig, dest: &Path, task: &SyncTask) -> Result<()> {
+    /*
+        It would have been less hacky to use libgit2 for this but it was 10-100x slower
+        which made cloning larger repos such as tldr really tedious imo.
+        So instead I opted to use the native git binary and manually parse the progress
+        output (see sync/progress.rs) to drive indicatif progress bars.
+        libgit2 is still around as a fallback for when git isn't installed, see sync/libgit2.rs
+    */
+
+    let mut cmd = git_command();
+    auth::configure_git(&mut cmd, repo)?;
+    cmd.arg("clone")
+        .arg("--progress");
+    if let Some(depth) = repo.depth {
+        cmd.arg(format!("--depth={depth}"));
+    }
+    if repo.bare {
+        cmd.arg("--bare");
+    }
+    else if repo.sparse_dirs().is_some() {
+        // Only fetch the blobs the sparse checkout actually needs
+        cmd.arg("--sparse")
+            .arg("--filter=blob:none");
+    }
+    cmd.arg(&repo.url)
+        .arg(dest);
+
+    let repo_name = repo.name();
+    task.progress.start(format!("Beginning cloning for {:?}", repo_name));
+
+    run_git(cmd, task, &format!("Cloning {:?}", repo_name))?;
+    if repo.bare {
+        // A bare clone has no remote tracking branches, let fetches update the branches themselves
+        let mut config = git_in(repo, dest)?;
+        config.arg("config")
+            .arg("remote.origin.fetch")
+            .arg(BARE_REFSPEC);
+        run_git(config, task, &format!("Configuring {:?}", repo_name))?;
+    }
+    else {
+        apply_sparse_checkout(repo, dest, task)?;
+    }
+    if let Some(git_ref) = &repo.git_ref {
+        checkout_pin(repo, git_ref, dest, task)?;
+    }
+
+    task.progress.finish(format!("✅ Finished cloning {:?}", repo_name));
+    Ok(())
+}
+
+fn update_repo(repo: &RepoConfig, dest: &Path, task: &SyncTask) -> Result<()> {
+    let repo_name = repo.name();
+    task.progress.start(format!("Fetching updates for {:?}", repo_name));
+
+    let is_shallow = Repository::open(dest)?.is_shallow();
+    let mut fetch = git_in(repo, dest)?;
+    fetch.arg("fetch")
+        .arg("--progress")
+        .arg("--tags")
+        .arg("--force");
+    match repo.depth {
+        Some(depth) => {
+            fetch.arg(format!("--depth={depth}"));
+        },
+        None if is_shallow => {
+            fetch.arg("--unshallow");
+        },
+        None => {},
+    }
+    fetch.arg("origin");
+    run_git(fetch, task, &format!("Fetching {:?}", repo_name))?;
+
+    if !repo.bare {
+        apply_sparse_checkout(repo, dest, task)?;
+    }
+    if let Some(git_ref) = &repo.git_ref {
+        checkout_pin(repo, git_ref, dest, task)?;
+    }
+    // Bring the checked out branch up to the upstream the fetch just moved
+    else if !repo.bare {
+        let git_repo = Repository::open(dest)?;
+        let mut update = git_in(repo, dest)?;
+        if git_repo.head_detached()? {
+            // Left detached by a pin or --locked, go back to following the default branch
+            let branch = default_branch(&git_repo)
+                .ok_or(anyhow!("{repo_name:?} has no default branch to go back to"))?;
+            update.arg("checkout")
+                .arg("--progress")
+                .arg("-B")
+                .arg(&branch)
+                .arg("--track")
+                .arg(format!("origin/{branch}"));
+        }
+        else if repo.depth.is_some() {
+            // A shallow fetch cuts the history so the old HEAD can't be fast-forwarded, just move the branch
+            let branch = git_repo
+                .head()?
+                .shorthand()
+                .map(String::from)
+                .ok_or(anyhow!("HEAD of {:?} is not a branch", repo_name))?;
+            update.arg("checkout")
+                .arg("--progress")
+                .arg("-B")
+                .arg(branch)
+                .arg("@{upstream}");
+        }
+        else {
+            update.arg("merge")
+                .arg("--ff-only")
+                .arg("--progress")
+                .arg("@{upstream}");
+        }
+        run_git(update, task, &format!("Updating {:?}", repo_name))?;
+    }
+
+    task.progress.finish(format!("✅ Finished updating {:?}", repo_name));
+    Ok(())
+}
+
+/// Runs a git command and drives the progress bar from its `--progress` output.
+fn run_git(mut cmd: Command, task: &SyncTask, action: &str) -> Result<()> {
+    cmd.stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    let mut child = cmd.spawn()?;
+    let stderr = child.stderr.take().expect("Failed to capture stderr");
+
+    // Read stderr on its own thread so a git process that stops talking can still be timed out
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stderr);
+        let mut temp = [0u8; 1024];
+        while let Ok(n) = reader.read(&mut temp) {
+            if n == 0 || sender.send(temp[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut parser = ProgressParser::default();
+    let mut output = String::new();
+    let handle_line = |line: GitLine, output: &mut String| match line {
+        GitLine::Progress(progress) => task.progress.progress(action, &progress),
+        GitLine::Output(text) => {
+            output.push_str(&text);
+            output.push('\n');
+        },
+    };
+
+    loop {
+        let timeout = task.deadline.saturating_duration_since(Instant::now());
+        let chunk = match receiver.recv_timeout(timeout) {
+            Ok(chunk) => chunk,
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(GitError {
+                    message: format!("{action} timed out"),
+                    stderr: output,
+                }.into());
+            },
+        };
+        for line in parser.feed(&chunk) {
+            handle_line(line, &mut output);
+        }
+    }
+    for line in parser.finish() {
+        handle_line(line, &mut output);
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(GitError {
+            message: format!("{action} failed, git exited with {status}"),
+            stderr: output,
+        }.into());
+    }
+
+    Ok(())
+}
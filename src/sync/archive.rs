@@ -0,0 +1,224 @@
+/*
+    Page sets that are published as an archive instead of a git repo, tldr ships
+    a tldr.zip for example. The archive gets downloaded (or copied for file:// urls)
+    and extracted in the staging dir, the ETag/Last-Modified of the server or the
+    mtime of the file are kept next to the pages so an unchanged archive is skipped.
+*/
+
+use std::fs::{ self, File };
+use std::io::{ self, Read, Write };
+use std::path::{ Path, PathBuf };
+use std::time::{ Instant, UNIX_EPOCH };
+use anyhow::{ Result, anyhow };
+use flate2::read::GzDecoder;
+use serde::{ Deserialize, Serialize };
+use ureq::Agent;
+use zip::ZipArchive;
+use crate::config::{ RepoConfig, archive_extension };
+use super::{ GitError, SyncAction, SyncTask };
+use super::progress::{ CloneState, GitProgress };
+
+/// Kept in the extracted dir, says where the pages came from.
+pub(super) const ARCHIVE_META: &str = ".pager_archive.toml";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ArchiveMeta {
+    url: String,
+    /// ETag of the server, or mtime and size of a local archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+}
+
+fn read_meta(dir: &Path) -> Option<ArchiveMeta> {
+    let meta = fs::read_to_string(dir.join(ARCHIVE_META)).ok()?;
+    toml::from_str(&meta).ok()
+}
+
+/// Whether `dir` holds pages extracted from the configured archive.
+pub(super) fn is_valid_extract(dir: &Path, repo: &RepoConfig) -> bool {
+    read_meta(dir).is_some_and(|meta| meta.url == repo.url)
+}
+
+/// Fetches the archive and extracts it into `dest`. None if it didn't change since it
+/// was extracted into `current_dir`.
+pub(super) fn sync_archive(repo: &RepoConfig, current_dir: &Path, dest: &Path, task: &SyncTask) -> Result<Option<SyncAction>> {
+    let repo_name = repo.name();
+    let previous = read_meta(current_dir).filter(|meta| meta.url == repo.url);
+    task.progress.start(format!("Downloading {repo_name:?}"));
+
+    let mut download = dest.as_os_str().to_owned();
+    download.push(".download");
+    let download = PathBuf::from(download);
+    let result = fetch_archive(repo, previous.as_ref(), &download, task)
+        .and_then(|meta| match meta {
+            Some(meta) => extract_archive(repo, &download, dest, task).map(|_| Some(meta)),
+            None => Ok(None),
+        });
+    let _ = fs::remove_file(&download);
+
+    let Some(meta) = result? else {
+        task.progress.finish(format!("✅ {repo_name:?} is up to date"));
+        return Ok(None);
+    };
+    fs::write(dest.join(ARCHIVE_META), toml::to_string_pretty(&meta)?)?;
+
+    task.progress.finish(format!("✅ Finished downloading {repo_name:?}"));
+    Ok(Some(match previous {
+        Some(_) => SyncAction::Updated,
+        None => SyncAction::Cloned,
+    }))
+}
+
+/// Downloads or copies the archive to `download`, None if it is unchanged.
+fn fetch_archive(repo: &RepoConfig, previous: Option<&ArchiveMeta>, download: &Path, task: &SyncTask) -> Result<Option<ArchiveMeta>> {
+    let action = format!("Downloading {:?}", repo.name());
+
+    if let Some(path) = repo.url.strip_prefix("file://") {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+        let etag = Some(format!("{mtime}-{}", metadata.len()));
+        if previous.is_some_and(|meta| meta.etag == etag) {
+            return Ok(None);
+        }
+
+        copy_with_progress(File::open(path)?, download, Some(metadata.len()), task, &action)?;
+        return Ok(Some(ArchiveMeta {
+            url: repo.url.clone(),
+            etag,
+            last_modified: None,
+        }));
+    }
+
+    let agent: Agent = Agent::config_builder()
+        .timeout_global(Some(task.deadline.saturating_duration_since(Instant::now())))
+        .http_status_as_error(false)
+        .build()
+        .into();
+    let mut request = agent.get(&repo.url);
+    if let Some(etag) = previous.and_then(|meta| meta.etag.as_deref()) {
+        request = request.header("If-None-Match", etag);
+    }
+    if let Some(last_modified) = previous.and_then(|meta| meta.last_modified.as_deref()) {
+        request = request.header("If-Modified-Since", last_modified);
+    }
+
+    let mut response = request.call().map_err(|err| archive_error(task, &action, err))?;
+    let status = response.status();
+    if status == 304 {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        return Err(anyhow!("{action} failed, the server answered {status}"));
+    }
+
+    let header = |name: &str| {
+        response.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let meta = ArchiveMeta {
+        url: repo.url.clone(),
+        etag: header("etag"),
+        last_modified: header("last-modified"),
+    };
+    let total = header("content-length").and_then(|length| length.parse().ok());
+    copy_with_progress(response.body_mut().as_reader(), download, total, task, &action)?;
+
+    Ok(Some(meta))
+}
+
+fn copy_with_progress(mut reader: impl Read, download: &Path, total: Option<u64>, task: &SyncTask, action: &str) -> Result<()> {
+    let mut file = File::create(download)?;
+    let mut buffer = [0u8; 64 * 1024];
+    let mut current = 0;
+    loop {
+        let n = reader.read(&mut buffer).map_err(|err| archive_error(task, action, err))?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buffer[..n])?;
+        current += n as u64;
+        task.progress.progress(action, &GitProgress {
+            phase: CloneState::Downloading,
+            remote: false,
+            current,
+            total,
+            done: false,
+        });
+
+        if Instant::now() >= task.deadline {
+            return Err(archive_error(task, action, "deadline reached"));
+        }
+    }
+
+    Ok(())
+}
+
+fn extract_archive(repo: &RepoConfig, download: &Path, dest: &Path, task: &SyncTask) -> Result<()> {
+    let action = format!("Extracting {:?}", repo.name());
+    fs::create_dir_all(dest)?;
+    let file = File::open(download)?;
+    let show_progress = |current, total| {
+        task.progress.progress(&action, &GitProgress {
+            phase: CloneState::Extracting,
+            remote: false,
+            current,
+            total,
+            done: false,
+        });
+    };
+
+    let tar: Box<dyn Read> = match archive_extension(&repo.url) {
+        Some(".zip") => {
+            let mut archive = ZipArchive::new(file)?;
+            let total = archive.len() as u64;
+            for i in 0..archive.len() {
+                let mut entry = archive.by_index(i)?;
+                // Entries that would end up outside of dest are skipped
+                let Some(path) = entry.enclosed_name().map(|name| dest.join(name)) else {
+                    continue;
+                };
+                if entry.is_dir() {
+                    fs::create_dir_all(&path)?;
+                }
+                else {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    io::copy(&mut entry, &mut File::create(&path)?)?;
+                }
+                show_progress(i as u64 + 1, Some(total));
+            }
+            return Ok(());
+        },
+        Some(".tar.gz" | ".tgz") => Box::new(GzDecoder::new(file)),
+        Some(".tar") => Box::new(file),
+        _ => return Err(anyhow!("{action} failed, {:?} is not a .zip, .tar.gz, .tgz or .tar file", repo.url)),
+    };
+
+    let mut archive = tar::Archive::new(tar);
+    for (i, entry) in archive.entries()?.enumerate() {
+        // unpack_in refuses paths that would end up outside of dest
+        entry?.unpack_in(dest)?;
+        show_progress(i as u64 + 1, None);
+    }
+
+    Ok(())
+}
+
+fn archive_error(task: &SyncTask, action: &str, err: impl std::fmt::Display) -> anyhow::Error {
+    let message = if Instant::now() >= task.deadline {
+        format!("{action} timed out")
+    }
+    else {
+        format!("{action} failed: {err}")
+    };
+
+    GitError {
+        message,
+        stderr: String::new(),
+    }.into()
+}
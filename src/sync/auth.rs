@@ -0,0 +1,108 @@
+/*
+    Credentials for private repos. The git backend gets them through the environment
+    of the git process and the libgit2 backend through a credentials callback. Tokens
+    are only ever read from the environment when git asks for them, so they never
+    end up in the config, on a command line or in the progress output.
+*/
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use anyhow::{ Result, anyhow };
+use git2::{ Cred, CredentialType };
+use crate::config::{ RepoAuth, RepoConfig };
+
+/// Username sent along with a token if none is configured, hosts only look at the token.
+const TOKEN_USERNAME: &str = "x-access-token";
+
+/// Sets up a git command to log in the way the repo is configured to.
+pub(super) fn configure_git(cmd: &mut Command, repo: &RepoConfig) -> Result<()> {
+    match &repo.auth {
+        None | Some(RepoAuth::CredentialHelper) => {},
+        Some(RepoAuth::SshKey { key }) => {
+            let key = key_path(repo, key)?;
+            let key = key.to_string_lossy().replace('\'', r"'\''");
+            cmd.env("GIT_SSH_COMMAND", format!("ssh -i '{key}' -o IdentitiesOnly=yes -o BatchMode=yes"));
+        },
+        Some(RepoAuth::SshAgent) => check_agent(repo)?,
+        Some(RepoAuth::TokenEnv { env: var, username }) => {
+            token(repo, var)?;
+            let username = username.as_deref().unwrap_or(TOKEN_USERNAME).replace('\'', r"'\''");
+            // The helper only names the variable, the shell git runs it with expands it
+            let helper = format!(
+                "!f() {{ test \"$1\" = get || return 0; echo 'username={username}'; echo \"password=${var}\"; }}; f"
+            );
+            // An empty helper clears the helpers git is configured with
+            cmd.env("GIT_CONFIG_COUNT", "2")
+                .env("GIT_CONFIG_KEY_0", "credential.helper")
+                .env("GIT_CONFIG_VALUE_0", "")
+                .env("GIT_CONFIG_KEY_1", "credential.helper")
+                .env("GIT_CONFIG_VALUE_1", helper);
+        },
+    }
+    Ok(())
+}
+
+/// Credentials callback for libgit2. It only answers once, libgit2 keeps asking
+/// for as long as the server turns the credentials down.
+pub(super) fn credentials(repo: &RepoConfig) -> impl FnMut(&str, Option<&str>, CredentialType) -> Result<Cred, git2::Error> + '_ {
+    let mut asked = false;
+    move |url, username_from_url, allowed| {
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username_from_url.unwrap_or("git"));
+        }
+        if asked {
+            return Err(git2::Error::from_str(&format!("authentication for {:?} was refused", repo.name())));
+        }
+        asked = true;
+
+        let username = username_from_url.unwrap_or("git");
+        let auth_error = |err: anyhow::Error| git2::Error::from_str(&err.to_string());
+        match &repo.auth {
+            Some(RepoAuth::SshKey { key }) => Cred::ssh_key(username, None, &key_path(repo, key).map_err(auth_error)?, None),
+            Some(RepoAuth::SshAgent) => {
+                check_agent(repo).map_err(auth_error)?;
+                Cred::ssh_key_from_agent(username)
+            },
+            Some(RepoAuth::TokenEnv { env: var, username }) => Cred::userpass_plaintext(
+                username.as_deref().unwrap_or(TOKEN_USERNAME),
+                &token(repo, var).map_err(auth_error)?,
+            ),
+            Some(RepoAuth::CredentialHelper) => credential_helper(url, username_from_url),
+            // Same as git without any auth settings, the agent for ssh and the helpers for https
+            None if allowed.contains(CredentialType::SSH_KEY) => Cred::ssh_key_from_agent(username),
+            None => credential_helper(url, username_from_url),
+        }
+    }
+}
+
+fn credential_helper(url: &str, username: Option<&str>) -> Result<Cred, git2::Error> {
+    Cred::credential_helper(&git2::Config::open_default()?, url, username)
+}
+
+/// Key path with a leading `~/` expanded, the key has to exist.
+fn key_path(repo: &RepoConfig, key: &str) -> Result<PathBuf> {
+    let path = match key.strip_prefix("~/") {
+        Some(rest) => env::home_dir()
+            .ok_or(anyhow!("User home dir could not be found"))?
+            .join(rest),
+        None => PathBuf::from(key),
+    };
+    if !path.is_file() {
+        return Err(anyhow!("SSH key {path:?} of {:?} does not exist", repo.name()));
+    }
+    Ok(path)
+}
+
+fn check_agent(repo: &RepoConfig) -> Result<()> {
+    env::var("SSH_AUTH_SOCK")
+        .map(|_| ())
+        .map_err(|_| anyhow!("{:?} logs in with the SSH agent but SSH_AUTH_SOCK is not set", repo.name()))
+}
+
+fn token(repo: &RepoConfig, var: &str) -> Result<String> {
+    match env::var(var) {
+        Ok(token) if !token.is_empty() => Ok(token),
+        _ => Err(anyhow!("{:?} logs in with a token from ${var} but it is not set", repo.name())),
+    }
+}
@@ -0,0 +1,236 @@
+/*
+    Sync backend built on libgit2, for when there is no git binary around.
+    Slower than git for large repos but drives the same progress bars through
+    the libgit2 callbacks instead of parsing git's stderr.
+*/
+
+use std::path::Path;
+use std::time::Instant;
+use anyhow::{ Result, anyhow };
+use git2::{ AutotagOption, BranchType, FetchOptions, RemoteCallbacks, Repository };
+use git2::build::{ CheckoutBuilder, RepoBuilder };
+use crate::config::RepoConfig;
+use super::{ BARE_REFSPEC, GitError, SyncTask, auth, default_branch, resolve_ref };
+use super::progress::{ CloneState, GitProgress };
+
+/// libgit2's GIT_FETCH_DEPTH_UNSHALLOW
+const UNSHALLOW: i32 = i32::MAX;
+
+pub(super) fn clone_repo(repo: &RepoConfig, dest: &Path, task: &SyncTask) -> Result<()> {
+    let repo_name = repo.name();
+    let action = format!("Cloning {repo_name:?}");
+    task.progress.start(format!("Beginning cloning for {repo_name:?}"));
+
+    let mut fetch_options = fetch_options(repo, task, &action);
+    if let Some(depth) = repo.depth {
+        fetch_options.depth(depth as i32);
+    }
+    let checkout = checkout_builder(repo, task, &action);
+    let git_repo = RepoBuilder::new()
+        .bare(repo.bare)
+        .fetch_options(fetch_options)
+        .with_checkout(checkout)
+        .clone(&repo.url, dest)
+        .map_err(|err| git_error(task, &action, err))?;
+
+    if repo.bare {
+        // Same as the git backend, fetches update the branches themselves
+        git_repo.config()?.set_str("remote.origin.fetch", BARE_REFSPEC)?;
+    }
+    if let Some(git_ref) = &repo.git_ref {
+        checkout_pin(repo, &git_repo, git_ref, task)?;
+    }
+
+    task.progress.finish(format!("✅ Finished cloning {repo_name:?}"));
+    Ok(())
+}
+
+pub(super) fn update_repo(repo: &RepoConfig, dest: &Path, task: &SyncTask) -> Result<()> {
+    let repo_name = repo.name();
+    task.progress.start(format!("Fetching updates for {repo_name:?}"));
+
+    let git_repo = Repository::open(dest)?;
+    let action = format!("Fetching {repo_name:?}");
+    let mut fetch_options = fetch_options(repo, task, &action);
+    match repo.depth {
+        Some(depth) => {
+            fetch_options.depth(depth as i32);
+        },
+        None if git_repo.is_shallow() => {
+            fetch_options.depth(UNSHALLOW);
+        },
+        None => {},
+    }
+    git_repo.find_remote("origin")?
+        .fetch(&[] as &[&str], Some(&mut fetch_options), None)
+        .map_err(|err| git_error(task, &action, err))?;
+
+    if let Some(git_ref) = &repo.git_ref {
+        checkout_pin(repo, &git_repo, git_ref, task)?;
+    }
+    // The fetch only moved refs/remotes/origin/*, move the checked out branch to its
+    // upstream and check out the new tree by hand
+    else if !repo.bare {
+        let action = format!("Updating {repo_name:?}");
+        let head = git_repo.head()?;
+        let detached = git_repo.head_detached()?;
+        let (branch_ref, upstream) = if detached {
+            // A pin or --locked left HEAD detached, go back to the default branch
+            let branch = default_branch(&git_repo)
+                .ok_or(anyhow!("{repo_name:?} has no default branch to go back to"))?;
+            (format!("refs/heads/{branch}"), format!("refs/remotes/origin/{branch}"))
+        }
+        else {
+            let branch_ref = head
+                .name()
+                .map(String::from)
+                .ok_or(anyhow!("HEAD of {repo_name:?} is not a branch"))?;
+            let upstream = git_repo
+                .branch_upstream_name(&branch_ref)
+                .ok()
+                .and_then(|name| name.as_str().map(String::from))
+                .ok_or(anyhow!("HEAD of {repo_name:?} has no upstream branch"))?;
+            (branch_ref, upstream)
+        };
+        let target = git_repo.refname_to_id(&upstream)?;
+
+        // With depth set the old HEAD may be cut out of the fetched history, so only full
+        // clones are held to a fast-forward
+        let old_head = head.peel_to_commit()?.id();
+        if !detached && repo.depth.is_none() && old_head != target && !git_repo.graph_descendant_of(target, old_head)? {
+            return Err(anyhow!("{action} failed, {upstream} is not a fast-forward of {branch_ref}"));
+        }
+
+        let mut checkout = checkout_builder(repo, task, &action);
+        git_repo.checkout_tree(&git_repo.find_object(target, None)?, Some(&mut checkout))
+            .map_err(|err| git_error(task, &action, err))?;
+        git_repo.reference(&branch_ref, target, true, "pager: fast-forward")?;
+        if detached {
+            let branch = branch_ref.trim_start_matches("refs/heads/");
+            git_repo.find_branch(branch, BranchType::Local)?
+                .set_upstream(Some(&format!("origin/{branch}")))?;
+        }
+        git_repo.set_head(&branch_ref)?;
+    }
+
+    task.progress.finish(format!("✅ Finished updating {repo_name:?}"));
+    Ok(())
+}
+
+/// Checks out the tree of the pinned commit and detaches HEAD there. A bare clone has no
+/// tree to check out, the branch HEAD points at is moved to the commit instead.
+fn checkout_pin(repo: &RepoConfig, git_repo: &Repository, git_ref: &str, task: &SyncTask) -> Result<()> {
+    let repo_name = repo.name();
+    let commit = match resolve_ref(git_repo, git_ref) {
+        Some(commit) => commit,
+        // Not fetched yet with depth set, fetch just that ref and take its commit from FETCH_HEAD
+        None if repo.depth.is_some() => {
+            let action = format!("Fetching {git_ref:?} for {repo_name:?}");
+            let mut fetch_options = fetch_options(repo, task, &action);
+            fetch_options.depth(repo.depth.unwrap_or(1) as i32);
+            git_repo.find_remote("origin")?
+                .fetch(&[git_ref], Some(&mut fetch_options), None)
+                .map_err(|err| git_error(task, &action, err))?;
+            git_repo.refname_to_id("FETCH_HEAD")?
+        },
+        None => return Err(anyhow!("Could not find ref {git_ref:?} in {:?}", git_repo.path())),
+    };
+
+    if !repo.bare {
+        let action = format!("Checking out {git_ref:?} in {repo_name:?}");
+        let mut checkout = checkout_builder(repo, task, &action);
+        git_repo.checkout_tree(&git_repo.find_object(commit, None)?, Some(&mut checkout))
+            .map_err(|err| git_error(task, &action, err))?;
+    }
+    let head_branch = git_repo.find_reference("HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().map(String::from));
+    match head_branch {
+        // Same as the git backend, a fetch moves the branch back so HEAD can stay on it
+        Some(branch) if repo.bare => {
+            git_repo.reference(&branch, commit, true, "pager: pin")?;
+        },
+        _ => git_repo.set_head_detached(commit)?,
+    }
+    Ok(())
+}
+
+/// Fetch options that log in as configured, move the progress bar and give up once
+/// the deadline has passed.
+fn fetch_options<'a>(repo: &'a RepoConfig, task: &'a SyncTask, action: &'a str) -> FetchOptions<'a> {
+    // The callbacks only run while data comes in, a stalled connection has to be cut
+    // by libgit2 itself
+    let timeout = task.deadline
+        .saturating_duration_since(Instant::now())
+        .as_millis()
+        .clamp(1, i32::MAX as u128) as i32;
+    // SAFETY: the options are plain ints libgit2 reads when it connects or reads, every
+    // task sets them from its own deadline which all start out the same
+    unsafe {
+        let _ = git2::opts::set_server_connect_timeout_in_milliseconds(timeout);
+        let _ = git2::opts::set_server_timeout_in_milliseconds(timeout);
+    }
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(auth::credentials(repo));
+    callbacks.transfer_progress(move |stats| {
+        let (phase, current, total) = if stats.received_objects() < stats.total_objects() {
+            (CloneState::ReceivingObjects, stats.received_objects(), stats.total_objects())
+        }
+        else {
+            (CloneState::ResolvingDeltas, stats.indexed_deltas(), stats.total_deltas())
+        };
+        task.progress.progress(action, &GitProgress {
+            phase,
+            remote: false,
+            current: current as u64,
+            total: Some(total as u64),
+            done: current == total,
+        });
+
+        Instant::now() < task.deadline
+    });
+    // Servers still counting objects only send sideband messages
+    callbacks.sideband_progress(|_| Instant::now() < task.deadline);
+
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks)
+        .download_tags(AutotagOption::All);
+    fetch_options
+}
+
+/// Safe checkout limited to the sparse dirs, that also restores files of dirs that were
+/// sparse before.
+fn checkout_builder<'a>(repo: &RepoConfig, task: &'a SyncTask, action: &str) -> CheckoutBuilder<'a> {
+    let mut checkout = CheckoutBuilder::new();
+    checkout.safe().recreate_missing(true);
+    for dir in repo.sparse_dirs().unwrap_or_default() {
+        checkout.path(dir);
+    }
+
+    let action = action.to_string();
+    checkout.progress(move |_, current, total| {
+        task.progress.progress(&action, &GitProgress {
+            phase: CloneState::UpdatingFiles,
+            remote: false,
+            current: current as u64,
+            total: Some(total as u64),
+            done: current == total,
+        });
+    });
+    checkout
+}
+
+fn git_error(task: &SyncTask, action: &str, err: git2::Error) -> anyhow::Error {
+    let message = if Instant::now() >= task.deadline {
+        format!("{action} timed out")
+    }
+    else {
+        format!("{action} failed: {}", err.message())
+    };
+
+    GitError {
+        message,
+        stderr: String::new(),
+    }.into()
+}
@@ -0,0 +1,161 @@
+/*
+    Where the progress of a sync goes. Progress bars are only readable in a terminal,
+    under cron or CI the same progress is written as plain lines, and wrappers can ask
+    for one JSON object per line on stdout instead.
+*/
+
+use std::io::{ self, IsTerminal };
+use std::sync::Mutex;
+use std::time::Duration;
+use anyhow::Result;
+use clap::ValueEnum;
+use indicatif::{ MultiProgress, ProgressBar, ProgressDrawTarget };
+use serde_json::json;
+use super::RepoReport;
+use super::progress::{ CloneState, GitProgress };
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// Bars when stderr is a terminal, plain lines otherwise
+    #[default]
+    Auto,
+    Bars,
+    /// A line on stderr whenever a repo starts a new phase
+    Plain,
+    /// A JSON object on stdout for every phase change and finished repo
+    Json,
+}
+
+impl ProgressMode {
+    pub fn resolve(self) -> ProgressMode {
+        match self {
+            ProgressMode::Auto if io::stderr().is_terminal() => ProgressMode::Bars,
+            ProgressMode::Auto => ProgressMode::Plain,
+            mode => mode,
+        }
+    }
+}
+
+/// Progress of a whole sync.
+pub(super) struct SyncOutput {
+    mode: ProgressMode,
+    multi_progress: MultiProgress,
+}
+
+impl SyncOutput {
+    pub(super) fn new(mode: ProgressMode) -> Self {
+        let mode = mode.resolve();
+        let multi_progress = match mode {
+            ProgressMode::Bars => MultiProgress::new(),
+            _ => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+        };
+
+        SyncOutput {
+            mode,
+            multi_progress,
+        }
+    }
+
+    pub(super) fn notice(&self, message: &str) -> Result<()> {
+        match self.mode {
+            ProgressMode::Json => println!("{}", json!({ "event": "notice", "message": message })),
+            ProgressMode::Plain => eprintln!("{message}"),
+            _ => self.multi_progress.println(message)?,
+        }
+        Ok(())
+    }
+
+    pub(super) fn repo(&self, name: String) -> RepoProgress {
+        let bar = self.multi_progress.add(ProgressBar::new_spinner());
+        bar.set_message(format!("Waiting to sync {name:?}"));
+
+        RepoProgress {
+            name,
+            mode: self.mode,
+            bar,
+            phase: Mutex::new(None),
+        }
+    }
+}
+
+/// Progress of a single repo, a bar or the lines/events standing in for it.
+pub(super) struct RepoProgress {
+    name: String,
+    mode: ProgressMode,
+    bar: ProgressBar,
+    /// Last reported action and phase, plain and JSON output only report changes
+    phase: Mutex<Option<(String, CloneState)>>,
+}
+
+impl RepoProgress {
+    pub(super) fn start(&self, message: String) {
+        self.bar.enable_steady_tick(Duration::from_millis(100));
+        self.message(message);
+    }
+
+    pub(super) fn message(&self, message: String) {
+        match self.mode {
+            ProgressMode::Json => println!("{}", json!({ "event": "status", "repo": self.name, "message": message })),
+            ProgressMode::Plain => eprintln!("{message}"),
+            _ => {},
+        }
+        self.bar.set_message(message);
+    }
+
+    pub(super) fn progress(&self, action: &str, progress: &GitProgress) {
+        let side = if progress.remote { " (remote)" } else { "" };
+        self.bar.set_length(progress.total.unwrap_or(progress.current));
+        self.bar.set_position(progress.current);
+        self.bar.set_style(progress.phase.style());
+        self.bar.set_message(format!("{action}: {}{side}", progress.phase.text()));
+
+        let mut phase = self.phase.lock().unwrap();
+        if phase.as_ref() == Some(&(action.to_string(), progress.phase)) {
+            return;
+        }
+        *phase = Some((action.to_string(), progress.phase));
+
+        match self.mode {
+            ProgressMode::Json => println!("{}", json!({
+                "event": "phase",
+                "repo": self.name,
+                "action": action,
+                "phase": progress.phase,
+                "remote": progress.remote,
+                "total": progress.total,
+            })),
+            ProgressMode::Plain => eprintln!("{action}: {}{side}", progress.phase.text()),
+            _ => {},
+        }
+    }
+
+    pub(super) fn finish(&self, message: String) {
+        if self.mode == ProgressMode::Plain {
+            eprintln!("{message}");
+        }
+        self.bar.set_style(CloneState::Finished.style());
+        self.bar.finish_with_message(message);
+    }
+
+    pub(super) fn fail(&self, message: String) {
+        if self.mode == ProgressMode::Plain {
+            eprintln!("{message}");
+        }
+        self.bar.abandon_with_message(message);
+    }
+
+    /// Completion event with the summary row of the repo.
+    pub(super) fn report(&self, report: &RepoReport) {
+        if self.mode == ProgressMode::Json {
+            println!("{}", json!({
+                "event": "finished",
+                "repo": report.name,
+                "status": report.action,
+                "commit": report.commit,
+                "pages": report.page_count,
+                "duration": report.duration.as_secs_f64(),
+                "error": report.error,
+            }));
+        }
+    }
+}
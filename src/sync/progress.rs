@@ -0,0 +1,197 @@
+/*
+    Parses the `--progress` output git writes to stderr. Progress lines are redrawn
+    with carriage returns and look like
+
+        remote: Counting objects:  40% (140/349)
+        Receiving objects: 100% (349/349), 637.91 KiB | 9.67 MiB/s, done.
+        remote: Enumerating objects: 349, done.
+
+    The phase names are translated by git, so every git command gets run in the C
+    locale to keep them parseable.
+*/
+
+use std::process::Command;
+use indicatif::ProgressStyle;
+use serde::Serialize;
+
+/// Phase of a clone or fetch, in the order git goes through them. Archives are
+/// downloaded and extracted instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloneState {
+    EnumeratingObjects,
+    CountingObjects,
+    CompressingObjects,
+    ReceivingObjects,
+    UnpackingObjects,
+    ResolvingDeltas,
+    UpdatingFiles,
+    Downloading,
+    Extracting,
+    Finished,
+}
+
+impl CloneState {
+    /// Phases as git names them in its progress output, the remote ones are prefixed with "remote: ".
+    const PHASES: [(&str, CloneState); 8] = [
+        ("Enumerating objects", CloneState::EnumeratingObjects),
+        ("Counting objects", CloneState::CountingObjects),
+        ("Compressing objects", CloneState::CompressingObjects),
+        ("Receiving objects", CloneState::ReceivingObjects),
+        ("Unpacking objects", CloneState::UnpackingObjects),
+        ("Resolving deltas", CloneState::ResolvingDeltas),
+        ("Updating files", CloneState::UpdatingFiles),
+        // Older gits call the checkout phase this
+        ("Checking out files", CloneState::UpdatingFiles),
+    ];
+
+    pub fn text(&self) -> &'static str {
+        match self {
+            CloneState::EnumeratingObjects => "Enumerating objects",
+            CloneState::CountingObjects => "Counting objects",
+            CloneState::CompressingObjects => "Compressing objects",
+            CloneState::ReceivingObjects => "Receiving objects",
+            CloneState::UnpackingObjects => "Unpacking objects",
+            CloneState::ResolvingDeltas => "Resolving deltas",
+            CloneState::UpdatingFiles => "Updating files",
+            CloneState::Downloading => "Downloading",
+            CloneState::Extracting => "Extracting",
+            CloneState::Finished => "Finished",
+        }
+    }
+
+    pub fn style(&self) -> ProgressStyle {
+        let template = match self {
+            CloneState::EnumeratingObjects
+            | CloneState::CountingObjects
+            | CloneState::CompressingObjects => "[{bar:40.magenta/blue}] {pos}/{len} {msg}",
+            CloneState::ReceivingObjects
+            | CloneState::UnpackingObjects => "[{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            CloneState::Downloading => "[{bar:40.cyan/blue}] {bytes}/{total_bytes} {msg}",
+            CloneState::ResolvingDeltas => "[{bar:40.yellow/cyan}] {pos}/{len} {msg}",
+            CloneState::UpdatingFiles
+            | CloneState::Extracting => "[{bar:40.green/yellow}] {pos}/{len} {msg}",
+            CloneState::Finished => "[{bar:40.green/yellow}] {pos}/{len} {msg}",
+        };
+
+        let progress_chars = match self {
+            CloneState::EnumeratingObjects
+            | CloneState::CountingObjects
+            | CloneState::CompressingObjects
+            | CloneState::ReceivingObjects
+            | CloneState::UnpackingObjects
+            | CloneState::Downloading => "##-",
+            CloneState::ResolvingDeltas => "=>#",
+            CloneState::UpdatingFiles
+            | CloneState::Extracting
+            | CloneState::Finished => "->=",
+        };
+
+        ProgressStyle::default_bar()
+            .template(template)
+            .unwrap()
+            .progress_chars(progress_chars)
+    }
+}
+
+/// One progress report of git.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GitProgress {
+    pub phase: CloneState,
+    /// Whether the phase ran on the remote end
+    pub remote: bool,
+    pub current: u64,
+    /// Phases like "Enumerating objects" only report a count
+    pub total: Option<u64>,
+    pub done: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GitLine {
+    Progress(GitProgress),
+    /// Anything that isn't progress, like errors or the refs that got updated
+    Output(String),
+}
+
+/// Splits git's stderr into lines as it comes in, chunks don't have to end on a line break.
+#[derive(Debug, Default)]
+pub struct ProgressParser {
+    buffer: Vec<u8>,
+}
+
+impl ProgressParser {
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<GitLine> {
+        self.buffer.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\r' || b == b'\n') {
+            let line = String::from_utf8_lossy(&self.buffer[..pos]).to_string();
+            self.buffer.drain(..=pos);
+            lines.extend(parse_line(&line));
+        }
+        lines
+    }
+
+    /// Whatever is left once git has exited.
+    pub fn finish(&mut self) -> Vec<GitLine> {
+        let line = String::from_utf8_lossy(&self.buffer).to_string();
+        self.buffer.clear();
+        parse_line(&line).into_iter().collect()
+    }
+}
+
+/// None for blank lines.
+pub fn parse_line(line: &str) -> Option<GitLine> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    Some(match parse_progress(trimmed) {
+        Some(progress) => GitLine::Progress(progress),
+        None => GitLine::Output(trimmed.to_string()),
+    })
+}
+
+fn parse_progress(line: &str) -> Option<GitProgress> {
+    let (remote, line) = match line.strip_prefix("remote:") {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, line),
+    };
+    let (name, rest) = line.split_once(':')?;
+    let phase = CloneState::PHASES
+        .iter()
+        .find(|(text, _)| *text == name)
+        .map(|(_, phase)| *phase)?;
+
+    let done = rest.trim_end().ends_with("done.");
+    let rest = rest.trim_start();
+    // Either "40% (140/349)..." or just "349, done."
+    let (current, total) = match rest.split_once('(') {
+        Some((percent, counts)) if percent.trim().ends_with('%') => {
+            let (current, total) = counts.split_once(')')?.0.split_once('/')?;
+            (current.trim().parse().ok()?, Some(total.trim().parse().ok()?))
+        },
+        _ => {
+            let count = rest.split(|c: char| !c.is_ascii_digit()).next()?;
+            (count.parse().ok()?, None)
+        },
+    };
+
+    Some(GitProgress {
+        phase,
+        remote,
+        current,
+        total,
+        done,
+    })
+}
+
+/// git with its output in English whatever the user's locale is, so it can be parsed.
+/// It never prompts for credentials either, nobody would see the prompt.
+pub fn git_command() -> Command {
+    let mut cmd = Command::new("git");
+    cmd.env("LC_ALL", "C")
+        .env("LANGUAGE", "")
+        .env("GIT_TERMINAL_PROMPT", "0");
+    cmd
+}